@@ -1,17 +1,24 @@
-use std::{time::Duration, fmt::Display};
+use std::{time::Duration, fmt::Display, pin::Pin, str::FromStr};
 
-use reqwest::{header::{HeaderMap, HeaderValue}, StatusCode};
+use eventsource_stream::Eventsource;
+use futures_util::{Stream, StreamExt};
+use reqwest::{header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE}, StatusCode};
 use serde::{de, Deserializer, Deserialize, Serialize, Serializer};
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 use tracing::trace;
+use async_trait::async_trait;
 use uuid::Uuid;
 
 use crate::*;
+use crate::config::BeamConfig;
 use crate::error::*;
 
 type BrokerId = String;
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// Longest label allowed in a DNS name, which Beam ids are modelled after.
+const MAX_LABEL_LENGTH: usize = 63;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ProxyId {
     proxy: String,
     broker: BrokerId,
@@ -23,45 +30,110 @@ pub struct AppId {
     rest: ProxyId,
 }
 
-impl ProxyId {
-    pub fn get_proxy_id(&self) -> String {
-        format!("{}.{}", &self.proxy, &self.broker)
+/// Checks a single dot-separated component of a Beam id: 1 to 63 ASCII letters, digits or hyphens, not starting or ending with a hyphen.
+fn validate_label(label: &str, kind: &str, full: &str) -> Result<(), ExecutorError> {
+    let invalid = |reason: &str| ExecutorError::InvalidBeamId(format!("Invalid {kind} {full:?}: {reason}"));
+    if label.is_empty() {
+        return Err(invalid("empty component"));
+    }
+    if label.len() > MAX_LABEL_LENGTH {
+        return Err(invalid(&format!("component {label:?} is longer than {MAX_LABEL_LENGTH} characters")));
+    }
+    if let Some(c) = label.chars().find(|c| !c.is_ascii_alphanumeric() && *c != '-') {
+        return Err(invalid(&format!("component {label:?} contains invalid character {c:?}")));
+    }
+    if label.starts_with('-') || label.ends_with('-') {
+        return Err(invalid(&format!("component {label:?} starts or ends with a hyphen")));
+    }
+    Ok(())
+}
+
+/// Splits `full` into its labels, requiring at least `min_components` valid ones.
+fn split_labels<'a>(full: &'a str, kind: &str, min_components: usize) -> Result<Vec<&'a str>, ExecutorError> {
+    let labels: Vec<&str> = full.split('.').collect();
+    for label in &labels {
+        validate_label(label, kind, full)?;
+    }
+    if labels.len() < min_components {
+        return Err(ExecutorError::InvalidBeamId(format!(
+            "Invalid {kind} {full:?}: expected at least {min_components} components, found {}",
+            labels.len()
+        )));
     }
+    Ok(labels)
+}
+
+impl ProxyId {
+    /// A proxy label followed by a broker id of at least two labels, e.g. proxy1.broker.samply.de
+    const MIN_COMPONENTS: usize = 3;
+
     pub fn get_broker_id(&self) -> String {
         self.broker.clone()
     }
     pub fn new(full: String) -> Result<Self, ExecutorError> {
-        let mut components: Vec<String> = full.split(".").map(|x| x.to_string()).collect();
-        let rest = components.split_off(1).join(".");
+        full.parse()
+    }
+}
+
+impl FromStr for ProxyId {
+    type Err = ExecutorError;
+
+    fn from_str(full: &str) -> Result<Self, Self::Err> {
+        let labels = split_labels(full, "ProxyId", Self::MIN_COMPONENTS)?;
         Ok(ProxyId {
-            proxy: components
-                .first()
-                .cloned()
-                .ok_or_else(|| ExecutorError::InvalidBeamId(format!("Invalid ProxyId: {}", full)))?,
-            broker: rest,
+            proxy: labels[0].to_string(),
+            broker: labels[1..].join("."),
         })
     }
 }
 
 impl AppId {
-    pub fn get_app_id(&self) -> String {
-        format!("{}.{}", &self.app, &self.rest.get_proxy_id())
-    }
-    pub fn get_proxy_id(&self) -> String {
-        self.rest.get_proxy_id()
+    /// An app label followed by a `ProxyId`, e.g. focus.proxy1.broker.samply.de
+    const MIN_COMPONENTS: usize = ProxyId::MIN_COMPONENTS + 1;
+
+    pub fn get_broker_id(&self) -> String {
+        self.rest.get_broker_id()
     }
     pub fn new(full: String) -> Result<Self, ExecutorError> {
-        let mut components: Vec<String> = full.split(".").map(|x| x.to_string()).collect();
-        let rest = components.split_off(1).join(".");
+        full.parse()
+    }
+    /// Whether both ids are registered with the same broker.
+    pub fn same_broker(&self, other: &AppId) -> bool {
+        self.rest.broker.eq_ignore_ascii_case(&other.rest.broker)
+    }
+}
+
+impl FromStr for AppId {
+    type Err = ExecutorError;
+
+    fn from_str(full: &str) -> Result<Self, Self::Err> {
+        let labels = split_labels(full, "AppId", Self::MIN_COMPONENTS)?;
         Ok(AppId {
-            app: components
-                .first()
-                .cloned()
-                .ok_or_else(|| ExecutorError::InvalidBeamId(format!("Invalid ProxyId: {}", full)))?,
-            rest: ProxyId::new(rest)?,
+            app: labels[0].to_string(),
+            rest: ProxyId {
+                proxy: labels[1].to_string(),
+                broker: labels[2..].join("."),
+            },
         })
     }
 }
+
+impl TryFrom<&str> for ProxyId {
+    type Error = ExecutorError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl TryFrom<&str> for AppId {
+    type Error = ExecutorError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
 impl Display for ProxyId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}.{}", self.proxy, self.broker)
@@ -84,8 +156,22 @@ impl Serialize for AppId {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: Serializer,
               {
-                  let mut state = String::serialize(&self.to_string(), serializer)?;
-                  Ok(state)
+                  String::serialize(&self.to_string(), serializer)
+              }
+}
+
+impl<'de> serde::Deserialize<'de> for ProxyId {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        ProxyId::new(s).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ProxyId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer,
+              {
+                  String::serialize(&self.to_string(), serializer)
               }
 }
 
@@ -93,11 +179,72 @@ impl Serialize for AppId {
 pub struct BeamTask {
     pub id: Uuid,
     pub from: AppId,
+    /// Other recipients' ids are not ours to validate, so those that are invalid are dropped instead of failing the task
+    #[serde(deserialize_with = "recipients")]
     pub to: Vec<AppId>,
     pub metadata: String,
     pub body: String,
-    pub ttl: String,
+    /// Remaining time to live as reported by the proxy at delivery, e.g. "10s"
+    #[serde(with = "ttl")]
+    pub ttl: Duration,
     pub failure_strategy: FailureStrategy,
+    /// When this task was received from the proxy; the ttl counts from here
+    #[serde(skip, default = "Instant::now")]
+    pub received: Instant,
+}
+
+impl BeamTask {
+    pub fn expires_at(&self) -> Instant {
+        self.received + self.ttl
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.ttl.is_zero() || Instant::now() >= self.expires_at()
+    }
+}
+
+fn recipients<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<AppId>, D::Error> {
+    let ids = Vec::<String>::deserialize(d)?;
+    Ok(ids.into_iter().filter_map(|id| id.parse().map_err(|e| warn!("Ignoring recipient of task: {e}")).ok()).collect())
+}
+
+/// Parses Beam's ttl notation: an integer followed by an optional unit (ms, s, m, h, d), defaulting to seconds.
+pub fn parse_ttl(ttl: &str) -> Result<Duration, ExecutorError> {
+    let ttl = ttl.trim();
+    let split = ttl.find(|c: char| !c.is_ascii_digit()).unwrap_or(ttl.len());
+    let (value, unit) = ttl.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| ExecutorError::ParsingError(format!("Invalid ttl: {ttl}")))?;
+    let seconds = match unit.trim() {
+        "ms" => return Ok(Duration::from_millis(value)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(ExecutorError::ParsingError(format!("Invalid ttl unit in {ttl}"))),
+    };
+    Ok(Duration::from_secs(value.saturating_mul(seconds)))
+}
+
+mod ttl {
+    use std::time::Duration;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    /// In seconds where possible, otherwise in milliseconds so that sub-second ttls are not cut off.
+    pub fn serialize<S: Serializer>(ttl: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        if ttl.subsec_millis() == 0 {
+            serializer.serialize_str(&format!("{}s", ttl.as_secs()))
+        } else {
+            serializer.serialize_str(&format!("{}ms", ttl.as_millis()))
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let s = String::deserialize(d)?;
+        super::parse_ttl(&s).map_err(de::Error::custom)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -131,6 +278,17 @@ pub enum Status {
     PermFailed,
 }
 
+impl FailureStrategy {
+    /// Total number of attempts (at least one) and the pause between them.
+    pub fn attempts(&self) -> (usize, Duration) {
+        match self {
+            FailureStrategy::Retry(Retry { backoff_millisecs, max_tries }) => {
+                ((*max_tries).max(1), Duration::from_millis(*backoff_millisecs as u64))
+            }
+        }
+    }
+}
+
 impl BeamResult {
     pub fn claimed(from: AppId, to: Vec<AppId>, task: Uuid) -> Self {
         Self {
@@ -153,6 +311,17 @@ impl BeamResult {
         }
     }
 
+    pub fn temp_failed(from: AppId, to: Vec<AppId>, task: Uuid, body: String) -> Self {
+        Self {
+            from,
+            to,
+            task,
+            status: Status::TempFailed,
+            metadata: "unused".to_owned(),
+            body,
+        }
+    }
+
     pub fn perm_failed(from: AppId, to: Vec<AppId>, task: Uuid, body: String) -> Self {
         Self {
             from,
@@ -165,125 +334,309 @@ impl BeamResult {
     }
 }
 
-pub async fn check_availability(config: &BeamConfig) {
-    let mut attempt: usize = 0;
+/// Access to the Beam proxy, abstracted so the task loop can run against an in-memory fake.
+#[async_trait]
+pub trait BeamApi: Send + Sync + 'static {
+    /// The AppId tasks are fetched for and results are sent from.
+    fn app_id(&self) -> &AppId;
 
-    debug!("Check Beam availability...");
+    /// Waits until the proxy reports to be healthy, giving up after a number of attempts.
+    async fn check_availability(&self);
 
-    loop {
-        let resp = match config.client
-            .get(format!("{}v1/health", config.beam_proxy_url))
-            .send()
-            .await
-        {
-            Ok(response) => response,
-            Err(e) => {
-                error!("Error making request: {:?}", e);
-                sleep(Duration::from_secs(3)).await;
-                continue;
-            }
-        };
+    async fn fetch_tasks(&self) -> Result<Vec<BeamTask>, ExecutorError>;
 
-        let status_code = resp.status();
-        let status_text = status_code.as_str();
-
-        if resp.status().is_success() {
-            debug!("Beam is available now.");
-            break;
-        } else if attempt == 10 {
-            debug!(
-                "Beam still not available after {} attempts.",
-                10
-            );
-            break;
-        } else {
-            debug!("Beam still not available, retrying in 3 seconds...");
-            sleep(Duration::from_secs(3)).await;
-            attempt += 1;
-        }
+    /// Opens a stream of incoming tasks; fails with `StreamingUnsupported` if the proxy cannot stream them.
+    async fn subscribe_tasks(&self) -> Result<TaskStream, ExecutorError> {
+        Err(ExecutorError::StreamingUnsupported("Task streaming is not implemented".into()))
+    }
+
+    async fn answer_task(&self, result: &BeamResult) -> Result<(), ExecutorError>;
+
+    async fn claim_task(&self, task: &BeamTask) -> Result<(), ExecutorError> {
+        debug!("Claiming task {}", task.id);
+        let result = BeamResult::claimed(self.app_id().clone(), vec![task.from.clone()], task.id);
+        self.answer_task(&result).await
     }
 }
 
-pub async fn retrieve_tasks(config: &BeamConfig) -> Result<Vec<BeamTask>, ExecutorError> {
-    trace!("Retrieve tasks...");
+pub type TaskStream = Pin<Box<dyn Stream<Item = Result<BeamTask, ExecutorError>> + Send>>;
 
-    let mut tasks: Vec<BeamTask> = Vec::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("ApiKey {} {}", config.app_id, config.app_key))
+#[derive(Debug, Clone)]
+pub struct BeamClient {
+    app_id: AppId,
+    auth: HeaderValue,
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl BeamClient {
+    pub fn new(config: &BeamConfig) -> Result<Self, ExecutorError> {
+        let auth = HeaderValue::from_str(&format!("ApiKey {} {}", config.app_id, config.app_key))
             .map_err(|e| {
                 ExecutorError::ConfigurationError(format!(
                     "Cannot assemble authorization header: {}",
                     e
                 ))
-            })?,
-    );
-
-    let url = format!(
-        "{}v1/tasks?filter=todo&wait_count=1&wait_time=10s",
-        config.beam_proxy_url
-    );
-    let resp = config.client
-        .get(&url)
-        .headers(headers)
-        .send()
-        .await
-        .map_err(|e| ExecutorError::UnableToRetrieveTasks(e))?;
-
-    let status_code = resp.status();
-
-    match status_code {
-        StatusCode::OK | StatusCode::PARTIAL_CONTENT => {
-            tasks = resp
-                .json::<Vec<BeamTask>>()
+            })?;
+        Ok(BeamClient {
+            app_id: config.app_id.clone(),
+            auth,
+            base_url: config.beam_proxy_url.to_string(),
+            client: config.client.clone(),
+        })
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, self.auth.clone());
+        headers
+    }
+
+    /// Parses one delivered task on its own, so that a malformed task does not hold up the others. It is answered as
+    /// `PermFailed` if its id and sender can still be read, otherwise only logged.
+    async fn parse_task(&self, value: serde_json::Value) -> Option<BeamTask> {
+        let error = match BeamTask::deserialize(&value) {
+            Ok(task) => return Some(task),
+            Err(e) => ExecutorError::ParsingError(format!("Invalid task: {e}")),
+        };
+        let id = value.get("id").and_then(|id| Uuid::deserialize(id).ok());
+        let from = value.get("from").and_then(|from| AppId::deserialize(from).ok());
+        let (Some(id), Some(from)) = (id, from) else {
+            warn!("Skipping task that cannot be answered: {error}");
+            return None;
+        };
+        warn!("Rejecting task {id} from {from}: {error}");
+        let result = BeamResult::perm_failed(self.app_id.clone(), vec![from], id, error.to_result_body());
+        if let Err(e) = self.answer_task(&result).await {
+            warn!("Error answering task {id}: {e}");
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl BeamApi for BeamClient {
+    fn app_id(&self) -> &AppId {
+        &self.app_id
+    }
+
+    async fn check_availability(&self) {
+        let mut attempt: usize = 0;
+
+        debug!("Check Beam availability...");
+
+        loop {
+            let resp = match self.client
+                .get(format!("{}v1/health", self.base_url))
+                .send()
                 .await
-                .map_err(|e| ExecutorError::UnableToParseTasks(e))?;
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Error making request: {:?}", e);
+                    sleep(Duration::from_secs(3)).await;
+                    continue;
+                }
+            };
+
+            if resp.status().is_success() {
+                debug!("Beam is available now.");
+                break;
+            } else if attempt == 10 {
+                debug!(
+                    "Beam still not available after {} attempts.",
+                    10
+                );
+                break;
+            } else {
+                debug!("Beam still not available, retrying in 3 seconds...");
+                sleep(Duration::from_secs(3)).await;
+                attempt += 1;
+            }
         }
-        _ => {
-            warn!("Unable to retrieve tasks: {}", status_code);
-            //return error
+    }
+
+    async fn fetch_tasks(&self) -> Result<Vec<BeamTask>, ExecutorError> {
+        trace!("Retrieve tasks...");
+
+        let mut tasks = Vec::new();
+        let url = format!(
+            "{}v1/tasks?filter=todo&wait_count=1&wait_time=10s",
+            self.base_url
+        );
+        let resp = self.client
+            .get(&url)
+            .headers(self.headers())
+            .send()
+            .await
+            .map_err(ExecutorError::UnableToRetrieveTasks)?;
+
+        let status_code = resp.status();
+
+        match status_code {
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT => {
+                let values = resp
+                    .json::<Vec<serde_json::Value>>()
+                    .await
+                    .map_err(ExecutorError::UnableToParseTasks)?;
+                for value in values {
+                    tasks.extend(self.parse_task(value).await);
+                }
+            }
+            _ => {
+                warn!("Unable to retrieve tasks: {}", status_code);
+                //return error
+            }
+        }
+        Ok(tasks)
+    }
+
+    async fn subscribe_tasks(&self) -> Result<TaskStream, ExecutorError> {
+        debug!("Subscribing to task stream...");
+
+        let resp = self.client
+            .get(format!("{}v1/tasks?filter=todo", self.base_url))
+            .headers(self.headers())
+            .header(ACCEPT, "text/event-stream")
+            .send()
+            .await
+            .map_err(ExecutorError::UnableToRetrieveTasks)?;
+
+        let status_code = resp.status();
+        let is_event_stream = matches!(
+            resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()),
+            Some(content_type) if content_type.starts_with("text/event-stream")
+        );
+        if status_code != StatusCode::OK || !is_event_stream {
+            return Err(ExecutorError::StreamingUnsupported(format!("Proxy answered with {} and content type {:?}", status_code, resp.headers().get(CONTENT_TYPE))));
         }
+
+        let client = self.clone();
+        let tasks = resp
+            .bytes_stream()
+            .eventsource()
+            .filter_map(move |event| {
+                let client = client.clone();
+                async move {
+                    match event {
+                        Ok(event) if event.data.trim().is_empty() => None,
+                        Ok(event) => match serde_json::from_str(&event.data) {
+                            Ok(value) => client.parse_task(value).await.map(Ok),
+                            Err(e) => Some(Err(ExecutorError::ParsingError(format!("Invalid task in event stream: {e}")))),
+                        },
+                        Err(e) => Some(Err(ExecutorError::StreamingFailed(e.to_string()))),
+                    }
+                }
+            });
+        Ok(Box::pin(tasks))
+    }
+
+    async fn answer_task(&self, result: &BeamResult) -> Result<(), ExecutorError> {
+        debug!("Answer task {} as {:?}", result.task, result.status);
+
+        let url = format!(
+            "{}v1/tasks/{}/results/{}",
+            self.base_url,
+            result.task,
+            self.app_id
+        );
+        let resp = self.client
+            .put(&url)
+            .headers(self.headers())
+            .json(result)
+            .send()
+            .await
+            .map_err(ExecutorError::UnableToAnswerTask)?;
+
+        let status_code = resp.status();
+
+        match status_code {
+            StatusCode::CREATED | StatusCode::NO_CONTENT => (),
+            _ =>  warn!("Unable to answer task {} : {}", result.task, status_code)
+            // Return Err
+        };
+        Ok(())
     }
-    Ok(tasks)
 }
 
-pub async fn answer_task_success(task: &BeamTask, config: &BeamConfig) -> Result<(),ExecutorError> {
-    debug!("Answer task {} as succeded", task.id);
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("ApiKey {} {}", config.app_id, config.app_key))
-            .map_err(|e| {
-                ExecutorError::ConfigurationError(format!(
-                    "Cannot assemble authorization header: {}",
-                    e
-                ))
-            })?,
-    );
-
-    let url = format!(
-        "{}v1/tasks/{}/results/{}",
-        config.beam_proxy_url,
-        task.id,
-        config.app_id
-    );
-    let body = BeamResult::succeeded(config.app_id.clone(), vec![task.from.clone()], task.id, "Started task".into());
-    let resp = config.client
-        .put(&url)
-        .headers(headers)
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| ExecutorError::UnableToAnswerTask(e))?;
-
-    let status_code = resp.status();
-
-    match status_code {
-        StatusCode::CREATED | StatusCode::NO_CONTENT => (),
-        _ =>  warn!("Unable to answer task {} : {}", task.id, status_code)
-        // Return Err
-    };
-    Ok(())
+    use super::*;
+
+    fn label() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9]([a-zA-Z0-9-]{0,20}[a-zA-Z0-9])?"
+    }
+
+    fn labels(min: usize) -> impl Strategy<Value = String> {
+        prop::collection::vec(label(), min..min + 4).prop_map(|labels| labels.join("."))
+    }
+
+    proptest! {
+        #[test]
+        fn app_id_round_trips(id in labels(AppId::MIN_COMPONENTS)) {
+            let app_id: AppId = id.parse().unwrap();
+            prop_assert_eq!(app_id.to_string(), id.clone());
+            let json = serde_json::to_string(&app_id).unwrap();
+            prop_assert_eq!(&json, &format!("\"{id}\""));
+            prop_assert_eq!(serde_json::from_str::<AppId>(&json).unwrap(), app_id.clone());
+            prop_assert_eq!(AppId::try_from(id.as_str()).unwrap(), app_id);
+        }
+
+        #[test]
+        fn proxy_id_round_trips(id in labels(ProxyId::MIN_COMPONENTS)) {
+            let proxy_id: ProxyId = id.parse().unwrap();
+            prop_assert_eq!(proxy_id.to_string(), id.clone());
+            let json = serde_json::to_string(&proxy_id).unwrap();
+            prop_assert_eq!(serde_json::from_str::<ProxyId>(&json).unwrap(), proxy_id.clone());
+            prop_assert_eq!(ProxyId::try_from(id.as_str()).unwrap(), proxy_id);
+        }
+
+        #[test]
+        fn ids_with_invalid_characters_are_rejected(id in labels(AppId::MIN_COMPONENTS), c in "[^a-zA-Z0-9.-]") {
+            let invalid = format!("{c}{id}");
+            prop_assert!(invalid.parse::<AppId>().is_err());
+            prop_assert!(invalid.parse::<ProxyId>().is_err());
+        }
+
+        #[test]
+        fn ttl_round_trips(millis in 0u64..100_000_000) {
+            let ttl = Duration::from_millis(millis);
+            let serialized = ttl::serialize(&ttl, serde_json::value::Serializer).unwrap();
+            prop_assert_eq!(parse_ttl(serialized.as_str().unwrap()).unwrap(), ttl);
+        }
+    }
+
+    #[test]
+    fn invalid_ids_are_rejected() {
+        for id in ["a..", "", "app.proxy.broker", "app..proxy.broker.de", "app.proxy.broker.de.", "-app.proxy.broker.de", "app.proxy-.broker.de", "app.pro_xy.broker.de"] {
+            assert!(matches!(AppId::try_from(id), Err(ExecutorError::InvalidBeamId(_))), "{id} should be invalid");
+        }
+        for id in ["proxy.broker", "proxy..de", ".proxy.broker.de"] {
+            assert!(ProxyId::try_from(id).is_err(), "{id} should be invalid");
+        }
+        assert!(AppId::try_from(format!("{}.proxy.broker.de", "a".repeat(64)).as_str()).is_err());
+        assert!(AppId::try_from(format!("{}.proxy.broker.de", "a".repeat(63)).as_str()).is_ok());
+    }
+
+    #[test]
+    fn broker_comparison() {
+        let app: AppId = "app.proxy1.broker.example.de".parse().unwrap();
+        assert_eq!(app.get_broker_id(), "broker.example.de");
+        assert!(app.same_broker(&"other.proxy2.Broker.example.de".parse().unwrap()));
+        assert!(!app.same_broker(&"app.proxy1.broker.example.com".parse().unwrap()));
+    }
+
+    #[test]
+    fn ttl_parsing() {
+        assert_eq!(parse_ttl("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_ttl("10").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_ttl("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_ttl("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(ttl::serialize(&Duration::from_millis(1500), serde_json::value::Serializer).unwrap(), "1500ms");
+        assert_eq!(ttl::serialize(&Duration::from_secs(10), serde_json::value::Serializer).unwrap(), "10s");
+        assert!(parse_ttl("s").is_err());
+        assert!(parse_ttl("10 years").is_err());
+    }
 }
@@ -0,0 +1,152 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::RwLock};
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{error::ExecutorError, format::Format, schema::parse_versioned, workflow::{ExecutorInfo, Workflow}};
+
+/// A vetted workflow the site offers to requesters, stored as one file in the catalog directory. Like task bodies,
+/// entries carry a `schema_version`, which `parse_versioned` upgrades them from.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct CatalogEntry {
+    pub name: String,
+    pub version: String,
+    pub executor: ExecutorInfo,
+    pub workflow: Workflow,
+}
+
+/// How a task body refers to a catalog entry.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub(crate) struct CatalogRef {
+    pub name: String,
+    pub version: String,
+}
+
+/// The site's local workflow catalog, loaded from a directory of `.json`, `.yaml` or `.yml` files.
+#[derive(Debug)]
+pub(crate) struct Catalog {
+    dir: Option<PathBuf>,
+    /// Whether tasks may bring their own `Workflow` instead of referring to the catalog
+    inline_allowed: bool,
+    entries: RwLock<HashMap<(String, String), CatalogEntry>>,
+}
+
+impl Catalog {
+    /// Loads all entries from `dir`. Without a directory the catalog is empty.
+    pub fn load(dir: Option<PathBuf>, inline_allowed: bool) -> Result<Self, ExecutorError> {
+        let entries = match &dir {
+            Some(dir) => read_entries(dir)?,
+            None => HashMap::new(),
+        };
+        if !inline_allowed && entries.is_empty() {
+            warn!("Inline workflows are disabled and the workflow catalog is empty, all tasks will be refused");
+        }
+        info!("Loaded {} workflows from the catalog", entries.len());
+        Ok(Catalog { dir, inline_allowed, entries: RwLock::new(entries) })
+    }
+
+    /// Re-reads the catalog directory. If it cannot be read, the previously loaded entries are kept.
+    pub fn reload(&self) -> Result<(), ExecutorError> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let entries = read_entries(dir)?;
+        info!("Reloaded {} workflows from the catalog", entries.len());
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+
+    pub fn inline_allowed(&self) -> bool {
+        self.inline_allowed
+    }
+
+    pub fn lookup(&self, reference: &CatalogRef) -> Option<CatalogEntry> {
+        self.entries.read().unwrap().get(&(reference.name.clone(), reference.version.clone())).cloned()
+    }
+}
+
+fn read_entries(dir: &Path) -> Result<HashMap<(String, String), CatalogEntry>, ExecutorError> {
+    let read_error = |e: std::io::Error| ExecutorError::ConfigurationError(format!("Cannot read workflow catalog {}: {e}", dir.display()));
+    let mut entries = HashMap::new();
+    for file in fs::read_dir(dir).map_err(read_error)? {
+        let path = file.map_err(read_error)?.path();
+        let Some(format) = Format::from_path(&path) else {
+            continue;
+        };
+        let content = fs::read_to_string(&path)
+            .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot read catalog file {}: {e}", path.display())))?;
+        let entry: CatalogEntry = parse_versioned(&content, format)
+            .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot parse catalog file {}: {e}", path.display())))?;
+        let key = (entry.name.clone(), entry.version.clone());
+        if entries.insert(key, entry).is_some() {
+            return Err(ExecutorError::ConfigurationError(format!("Catalog file {} repeats an existing name and version", path.display())));
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{error::ExecutorError, test_support::{test_task, TempDir, WORKFLOW}, workflow::ExecutionTask};
+
+    const ENTRY: &str = r#"{"schema_version":2,"name":"count","version":"1.0","executor":"DockerExecutor","workflow":{"output":["out.csv"],"steps":[{"name":"s","image":"i","env":null,"input":null,"output":"out.csv"}]}}"#;
+
+    const YAML_ENTRY: &str = "
+name: count
+version: '3.0'
+executor:
+  name: DockerExecutor
+workflow:
+  output: [out.csv]
+  steps:
+    - name: s
+      image: i
+      env: null
+      input: null
+      output: out.csv
+";
+
+    #[test]
+    fn loads_and_reloads_entries() {
+        let dir = TempDir::new("catalog");
+        fs::write(dir.join("count.json"), ENTRY).unwrap();
+        fs::write(dir.join("README.md"), "not a workflow").unwrap();
+        let reference = |version: &str| CatalogRef { name: "count".into(), version: version.into() };
+
+        let catalog = Catalog::load(Some(dir.to_path_buf()), false).unwrap();
+        assert!(!catalog.inline_allowed());
+        assert_eq!(catalog.lookup(&reference("1.0")).unwrap().workflow.steps[0].image, "i");
+        assert!(catalog.lookup(&reference("2.0")).is_none());
+
+        fs::write(dir.join("count-2.json"), ENTRY.replace("1.0", "2.0")).unwrap();
+        fs::write(dir.join("count-3.yaml"), YAML_ENTRY).unwrap();
+        catalog.reload().unwrap();
+        assert!(catalog.lookup(&reference("2.0")).is_some());
+        assert_eq!(catalog.lookup(&reference("3.0")).unwrap().workflow.steps[0].output, "out.csv");
+
+        fs::write(dir.join("broken.json"), "{").unwrap();
+        assert!(catalog.reload().is_err());
+        assert!(catalog.lookup(&reference("2.0")).is_some());
+    }
+
+    #[test]
+    fn tasks_refer_to_entries() {
+        let dir = TempDir::new("catalog");
+        fs::write(dir.join("count.json"), ENTRY).unwrap();
+        let catalog = Catalog::load(Some(dir.to_path_buf()), false).unwrap();
+        drop(dir);
+
+        let task = ExecutionTask::parse(test_task(r#"{"catalog":{"name":"count","version":"1.0"}}"#, Duration::from_secs(10)), &catalog).unwrap();
+        assert_eq!(task.workflow.output, vec!["out.csv"]);
+        let unknown = ExecutionTask::parse(test_task(r#"{"catalog":{"name":"count","version":"3.0"}}"#, Duration::from_secs(10)), &catalog);
+        assert!(matches!(unknown, Err(ExecutorError::ValidationFailed(_))));
+        let inline = ExecutionTask::parse(test_task(WORKFLOW, Duration::from_secs(10)), &catalog);
+        assert!(matches!(inline, Err(ExecutorError::TaskRejected(_))));
+        let both = ExecutionTask::parse(test_task(&WORKFLOW.replacen('{', r#"{"catalog":{"name":"count","version":"1.0"},"#, 1), Duration::from_secs(10)), &catalog);
+        assert!(matches!(both, Err(ExecutorError::ParsingError(_))));
+    }
+}
@@ -1,11 +1,12 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
 
-use http::{Uri, HeaderValue};
-use reqwest::{Proxy, Client, Certificate};
+use http::Uri;
+use reqwest::{Proxy, Certificate};
 use tracing::{debug, info, warn};
 use clap::Parser;
+use tokio::sync::Semaphore;
 
-use crate::{error::ExecutorError, beam::AppId};
+use crate::{catalog::Catalog, docker_executor::{load_delegate_profiles, parse_env_entry, DelegateProfile, DELEGATE_IMAGE}, error::ExecutorError, executor::ExecutorRegistry, hpc_executor::SlurmSettings, local_executor::{parse_local_command, LocalSettings}, beam::{parse_ttl, AppId}, policy::{ImagePolicy, SenderAllowlist}, pull::{ImagePullPolicy, PullSettings, RegistryCredentials}, workflow::{parse_byte_size, SiteLimits, StepFailurePolicy, StepResources}};
 
 const CLAP_FOOTER: &str = "For proxy support, environment variables HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY (and their lower-case variants) are supported. Usually, you want to set HTTP_PROXY *and* HTTPS_PROXY or set ALL_PROXY if both values are the same.\n\nFor updates and detailed usage instructions, visit https://github.com/tkussel/bk-orchestrator";
 
@@ -27,6 +28,158 @@ struct CliArgs {
     /// Outgoing HTTP proxy: Directory with CA certificates to trust for TLS connections (e.g. /etc/samply/cacerts/)
     #[clap(long, short='c', env, value_parser)]
     tls_ca_certificates_dir: Option<PathBuf>,
+
+    /// Receive tasks through the proxy's Server-Sent Events stream instead of long-polling; falls back to polling if the proxy does not support it
+    #[clap(long, env, value_parser, default_value_t = false)]
+    beam_task_stream: bool,
+
+    /// Beam AppIds allowed to send workflow tasks, comma separated; wildcards like *.broker.example.de are supported. If unset, all senders are accepted
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    allowed_senders: Vec<String>,
+
+    /// File to record the state of received tasks in, so that re-delivered tasks are not executed twice even across restarts
+    #[clap(long, env, value_parser)]
+    state_file: Option<PathBuf>,
+
+    /// Executors to offer, comma separated; tasks naming other executors are refused
+    #[clap(long, env, value_parser, value_delimiter = ',', default_value = "DockerExecutor")]
+    executors: Vec<String>,
+
+    /// HPCExecutor: directory shared with the Slurm compute nodes that runs are kept in
+    #[clap(long, env, value_parser)]
+    slurm_work_dir: Option<PathBuf>,
+
+    /// HPCExecutor: directory containing sbatch, sacct and scancel; by default they are looked up in PATH
+    #[clap(long, env, value_parser)]
+    slurm_bin_dir: Option<PathBuf>,
+
+    /// HPCExecutor: partition to submit jobs to, if not the cluster's default
+    #[clap(long, env, value_parser)]
+    slurm_partition: Option<String>,
+
+    /// HPCExecutor: how often to ask sacct for the state of running jobs
+    #[clap(long, env, value_parser = parse_ttl, default_value = "10s")]
+    slurm_poll_interval: Duration,
+
+    /// HPCExecutor: command running the steps' images on the compute nodes, called like apptainer
+    #[clap(long, env, value_parser, default_value = "apptainer")]
+    slurm_container_runtime: String,
+
+    /// LocalExecutor: directory the runs' working directories are created in
+    #[clap(long, env, value_parser)]
+    local_work_dir: Option<PathBuf>,
+
+    /// LocalExecutor: programs workflow steps may run, comma separated, as name=/absolute/path; steps refer to them by name in their image
+    #[clap(long, env, value_parser = parse_local_command, value_delimiter = ',')]
+    local_commands: Vec<(String, PathBuf)>,
+
+    /// Hand workflows to a delegate orchestrator container instead of running each step as its own container
+    #[clap(long, env, value_parser, default_value_t = false)]
+    docker_delegate: bool,
+
+    /// Image of the delegate orchestrator
+    #[clap(long, env, value_parser, default_value = DELEGATE_IMAGE)]
+    delegate_image: String,
+
+    /// Command of the delegate orchestrator as a JSON array, e.g. ["orchestrate", "--stdin"]; defaults to the image's CMD
+    #[clap(long, env, value_parser = parse_json_list)]
+    delegate_command: Option<Vec<String>>,
+
+    /// Entrypoint of the delegate orchestrator as a JSON array; defaults to the image's ENTRYPOINT
+    #[clap(long, env, value_parser = parse_json_list)]
+    delegate_entrypoint: Option<Vec<String>>,
+
+    /// Additional environment of the delegate orchestrator, comma separated KEY=value entries
+    #[clap(long, env, value_parser = parse_env_entry, value_delimiter = ',')]
+    delegate_env: Vec<String>,
+
+    /// Working directory of the delegate orchestrator; defaults to the image's
+    #[clap(long, env, value_parser)]
+    delegate_working_dir: Option<String>,
+
+    /// Allocate a TTY for the delegate orchestrator
+    #[clap(long, env, value_parser, default_value_t = true, action = clap::ArgAction::Set)]
+    delegate_tty: bool,
+
+    /// Send the workflow to the delegate orchestrator's stdin; if disabled, it is passed in BK_WORKFLOW
+    #[clap(long, env, value_parser, default_value_t = true, action = clap::ArgAction::Set)]
+    delegate_stdin: bool,
+
+    /// JSON or YAML file of further delegate orchestrator profiles by executor name, each with image, command, entrypoint, env, working_dir, tty and stdin. Executors listed in --executors may refer to them
+    #[clap(long, env, value_parser)]
+    delegate_profiles: Option<PathBuf>,
+
+    /// Maximum number of workflow steps running at the same time, across all workflows
+    #[clap(long, env, value_parser, default_value_t = 4)]
+    max_parallel_steps: usize,
+
+    /// What to do with steps running in parallel branches once a step of the same workflow fails
+    #[clap(long, env, value_enum, default_value = "cancel")]
+    on_step_failure: StepFailurePolicy,
+
+    /// Maximum number of CPUs a workflow step may use, fractions are allowed
+    #[clap(long, env, value_parser)]
+    max_step_cpus: Option<f64>,
+
+    /// Maximum memory a workflow step may use, e.g. 2G
+    #[clap(long, env, value_parser = parse_byte_size)]
+    max_step_memory: Option<u64>,
+
+    /// Maximum number of processes in a workflow step
+    #[clap(long, env, value_parser)]
+    max_step_pids: Option<i64>,
+
+    /// Maximum size of the tmpfs mounted at /tmp in a workflow step, e.g. 512M
+    #[clap(long, env, value_parser = parse_byte_size)]
+    max_step_tmpfs: Option<u64>,
+
+    /// Maximum size of a workflow step's writable container layer, e.g. 10G; requires a storage driver that supports size quotas
+    #[clap(long, env, value_parser = parse_byte_size)]
+    max_step_disk: Option<u64>,
+
+    /// Timeout of workflow steps that do not set one, e.g. 30m
+    #[clap(long, env, value_parser = parse_ttl)]
+    default_step_timeout: Option<Duration>,
+
+    /// Maximum timeout a workflow step may set, e.g. 2h
+    #[clap(long, env, value_parser = parse_ttl)]
+    max_step_timeout: Option<Duration>,
+
+    /// Maximum timeout a workflow may set; also applies to workflows that do not set one, e.g. 12h
+    #[clap(long, env, value_parser = parse_ttl)]
+    max_workflow_timeout: Option<Duration>,
+
+    /// When to pull the images of workflow steps and delegate orchestrators before a run
+    #[clap(long, env, value_enum, default_value = "missing")]
+    image_pull_policy: ImagePullPolicy,
+
+    /// Docker config.json to read registry credentials for pulling images from, e.g. /root/.docker/config.json
+    #[clap(long, env, value_parser)]
+    registry_auth_file: Option<PathBuf>,
+
+    /// Directory of per-registry credential files named <registry>.json, e.g. registry.example.de.json, each holding username and password or an identitytoken; they take precedence over --registry-auth-file
+    #[clap(long, env, value_parser)]
+    registry_credentials_dir: Option<PathBuf>,
+
+    /// Directory of vetted workflows that tasks can refer to by name and version; reloaded on SIGHUP
+    #[clap(long, env, value_parser)]
+    catalog_dir: Option<PathBuf>,
+
+    /// Image repositories workflows may run, comma separated; wildcards like registry.example.de/* are supported. Images without registry are on docker.io, e.g. docker.io/library/alpine. If unset, all images are allowed
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    allowed_images: Vec<String>,
+
+    /// Refuse images that are not referenced by an @sha256: digest
+    #[clap(long, env, value_parser, default_value_t = false)]
+    require_image_digest: bool,
+
+    /// Pin images referenced by tag to the digest of the local image before a run starts
+    #[clap(long, env, value_parser, default_value_t = false)]
+    resolve_image_digests: bool,
+
+    /// Only run workflows from the catalog and refuse tasks that bring their own workflow
+    #[clap(long, env, value_parser, default_value_t = false)]
+    catalog_only: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +188,22 @@ pub struct BeamConfig {
     pub(crate) app_key: String,
     pub beam_proxy_url: Uri,
     pub(crate) client: reqwest::Client,
+    pub task_stream: bool,
+    pub allowed_senders: SenderAllowlist,
+    pub state_file: Option<PathBuf>,
+    pub docker_delegate: bool,
+    /// The delegate `DockerExecutor` starts with `--docker-delegate`
+    pub delegate: DelegateProfile,
+    pub delegate_profiles: BTreeMap<String, DelegateProfile>,
+    /// Shared by all runs to enforce `--max-parallel-steps`
+    pub step_slots: Arc<Semaphore>,
+    pub on_step_failure: StepFailurePolicy,
+    pub limits: SiteLimits,
+    pub pull: PullSettings,
+    pub catalog: Arc<Catalog>,
+    pub slurm: SlurmSettings,
+    pub local: LocalSettings,
+    pub executors: Arc<ExecutorRegistry>,
 }
 
 impl BeamConfig {
@@ -46,16 +215,68 @@ impl BeamConfig {
             .map_err(|e| ExecutorError::ConfigurationError(format!("Unable to read from TLS CA directory: {}", e)))?;
         debug!("Post loading");
         let client = prepare_reqwest_client(&tls_ca_certificates)?;
-        let config = BeamConfig {
+        let mut config = BeamConfig {
             beam_proxy_url: cli_args.beam_proxy_url,
             app_id: AppId::new(cli_args.beam_app_id)?,
             app_key: cli_args.beam_api_key,
-            client
+            client,
+            task_stream: cli_args.beam_task_stream,
+            allowed_senders: SenderAllowlist::new(cli_args.allowed_senders),
+            state_file: cli_args.state_file,
+            docker_delegate: cli_args.docker_delegate,
+            delegate: DelegateProfile {
+                image: cli_args.delegate_image,
+                command: cli_args.delegate_command,
+                entrypoint: cli_args.delegate_entrypoint,
+                env: cli_args.delegate_env,
+                working_dir: cli_args.delegate_working_dir,
+                tty: cli_args.delegate_tty,
+                stdin: cli_args.delegate_stdin,
+            },
+            delegate_profiles: cli_args.delegate_profiles.as_deref().map(load_delegate_profiles).transpose()?.unwrap_or_default(),
+            step_slots: Arc::new(Semaphore::new(cli_args.max_parallel_steps.max(1))),
+            on_step_failure: cli_args.on_step_failure,
+            limits: SiteLimits {
+                resources: StepResources {
+                    cpus: cli_args.max_step_cpus,
+                    memory: cli_args.max_step_memory,
+                    pids: cli_args.max_step_pids,
+                    tmpfs: cli_args.max_step_tmpfs,
+                    disk: cli_args.max_step_disk,
+                },
+                default_step_timeout: cli_args.default_step_timeout,
+                max_step_timeout: cli_args.max_step_timeout,
+                max_workflow_timeout: cli_args.max_workflow_timeout,
+                images: ImagePolicy::new(cli_args.allowed_images, cli_args.require_image_digest, cli_args.resolve_image_digests),
+            },
+            pull: PullSettings {
+                policy: cli_args.image_pull_policy,
+                credentials: Arc::new(RegistryCredentials::load(cli_args.registry_auth_file.as_deref(), cli_args.registry_credentials_dir.as_deref())?),
+            },
+            catalog: Arc::new(Catalog::load(cli_args.catalog_dir, !cli_args.catalog_only)?),
+            slurm: SlurmSettings {
+                work_dir: cli_args.slurm_work_dir,
+                bin_dir: cli_args.slurm_bin_dir,
+                partition: cli_args.slurm_partition,
+                poll_interval: cli_args.slurm_poll_interval,
+                container_runtime: cli_args.slurm_container_runtime,
+            },
+            local: LocalSettings {
+                work_dir: cli_args.local_work_dir,
+                commands: cli_args.local_commands.into_iter().collect(),
+            },
+            executors: Arc::default(),
         };
+        // Executors are configured from the rest of the configuration
+        config.executors = Arc::new(ExecutorRegistry::load(&cli_args.executors, &config)?);
         Ok(config)
     }
 }
 
+/// Parses a command given as JSON array of strings.
+fn parse_json_list(value: &str) -> Result<Vec<String>, String> {
+    serde_json::from_str(value).map_err(|e| format!("expected a JSON array of strings: {e}"))
+}
 
 pub fn load_certificates_from_dir(ca_dir: Option<PathBuf>) -> Result<Vec<Certificate>, std::io::Error> {
     let mut result = Vec::new();
@@ -89,9 +310,9 @@ pub fn prepare_reqwest_client(certs: &Vec<Certificate>) -> Result<reqwest::Clien
         for (k,v) in std::env::vars().filter(|(k,_)| k.to_lowercase() == var) {
             std::env::set_var(k.to_uppercase(), v.clone());
             match k.as_str() {
-                "http_proxy" => proxies.push(Proxy::http(v).map_err(|e|ExecutorError::InvalidProxyConfig(e))?.no_proxy(no_proxy.clone())),
-                "https_proxy" => proxies.push(Proxy::https(v).map_err(|e|ExecutorError::InvalidProxyConfig(e))?.no_proxy(no_proxy.clone())),
-                "all_proxy" => proxies.push(Proxy::all(v).map_err(|e|ExecutorError::InvalidProxyConfig(e))?.no_proxy(no_proxy.clone())),
+                "http_proxy" => proxies.push(Proxy::http(v).map_err(ExecutorError::InvalidProxyConfig)?.no_proxy(no_proxy.clone())),
+                "https_proxy" => proxies.push(Proxy::https(v).map_err(ExecutorError::InvalidProxyConfig)?.no_proxy(no_proxy.clone())),
+                "all_proxy" => proxies.push(Proxy::all(v).map_err(ExecutorError::InvalidProxyConfig)?.no_proxy(no_proxy.clone())),
                 _ => ()
             };
         }
@@ -0,0 +1,132 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{error::ExecutorError, workflow::Workflow};
+
+/// Dependencies between the steps of a workflow, derived from the names of the files they read and write.
+/// Steps are referred to by their index in `Workflow.steps`.
+#[derive(Debug, Clone)]
+pub(crate) struct WorkflowGraph {
+    /// For every step, the steps producing its inputs
+    pub dependencies: Vec<Vec<usize>>,
+    /// For every step, the steps reading its output
+    pub dependents: Vec<Vec<usize>>,
+    /// All steps, each one after the steps it depends on
+    pub order: Vec<usize>,
+}
+
+impl WorkflowGraph {
+    pub fn build(workflow: &Workflow) -> Result<Self, ExecutorError> {
+        let mut producers: HashMap<&str, usize> = HashMap::new();
+        for (index, step) in workflow.steps.iter().enumerate() {
+            if let Some(other) = producers.insert(&step.output, index) {
+                return Err(ExecutorError::InvalidWorkflow(format!(
+                    "Steps {} and {} both produce {}",
+                    workflow.steps[other].name, step.name, step.output
+                )));
+            }
+        }
+
+        let mut dependencies = Vec::with_capacity(workflow.steps.len());
+        for step in &workflow.steps {
+            let mut deps = Vec::new();
+            for input in step.inputs() {
+                let producer = producers.get(input.as_str()).ok_or_else(|| {
+                    ExecutorError::InvalidWorkflow(format!("No step produces {}, the input of step {}", input, step.name))
+                })?;
+                if !deps.contains(producer) {
+                    deps.push(*producer);
+                }
+            }
+            dependencies.push(deps);
+        }
+
+        let mut dependents = vec![Vec::new(); dependencies.len()];
+        for (step, deps) in dependencies.iter().enumerate() {
+            for dep in deps {
+                dependents[*dep].push(step);
+            }
+        }
+        let order = topological_order(&dependencies, &dependents).map_err(|unordered| {
+            let names: Vec<&str> = unordered.iter().map(|step| workflow.steps[*step].name.as_str()).collect();
+            ExecutorError::InvalidWorkflow(format!("The inputs and outputs of steps {} form a cycle", names.join(", ")))
+        })?;
+        Ok(WorkflowGraph { dependencies, dependents, order })
+    }
+
+    /// Steps that do not depend on any other step.
+    pub fn roots(&self) -> impl Iterator<Item = usize> + '_ {
+        self.order.iter().copied().filter(|step| self.dependencies[*step].is_empty())
+    }
+
+    /// The step producing `file`, if any.
+    pub fn producer(workflow: &Workflow, file: &str) -> Option<usize> {
+        workflow.steps.iter().position(|step| step.output == file)
+    }
+}
+
+/// Kahn's algorithm, keeping the declaration order among independent steps. On cycles, returns the steps that
+/// could not be ordered, i.e. the steps in a cycle and the steps depending on them.
+fn topological_order(dependencies: &[Vec<usize>], dependents: &[Vec<usize>]) -> Result<Vec<usize>, Vec<usize>> {
+    let mut remaining: Vec<usize> = dependencies.iter().map(Vec::len).collect();
+    let mut ready: VecDeque<usize> = (0..dependencies.len()).filter(|step| remaining[*step] == 0).collect();
+    let mut order = Vec::with_capacity(dependencies.len());
+    while let Some(step) = ready.pop_front() {
+        order.push(step);
+        for dependent in &dependents[step] {
+            remaining[*dependent] -= 1;
+            if remaining[*dependent] == 0 {
+                ready.push_back(*dependent);
+            }
+        }
+    }
+    if order.len() == dependencies.len() {
+        Ok(order)
+    } else {
+        Err((0..dependencies.len()).filter(|step| !order.contains(step)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workflow::WorkflowSteps;
+
+    fn step(name: &str, input: &[&str], output: &str) -> WorkflowSteps {
+        WorkflowSteps {
+            name: name.into(),
+            image: format!("{name}:latest"),
+            env: None,
+            input: Some(input.iter().map(|i| i.to_string()).collect()),
+            output: output.into(),
+            resources: None,
+            timeout: None,
+        }
+    }
+
+    fn workflow(steps: Vec<WorkflowSteps>) -> Workflow {
+        Workflow { output: vec![], steps, max_parallelism: None, timeout: None, parameters: Default::default() }
+    }
+
+    #[test]
+    fn orders_steps_by_dependencies() {
+        let workflow = workflow(vec![
+            step("print", &["merged.csv"], "output.pdf"),
+            step("merge", &["data1.csv", "data2.csv"], "merged.csv"),
+            step("import1", &[], "data1.csv"),
+            step("import2", &[], "data2.csv"),
+        ]);
+        let graph = WorkflowGraph::build(&workflow).unwrap();
+        assert_eq!(graph.order, vec![2, 3, 1, 0]);
+        assert_eq!(graph.dependencies[1], vec![2, 3]);
+        assert_eq!(graph.dependents[2], vec![1]);
+        assert_eq!(graph.roots().collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn detects_cycles_and_missing_producers() {
+        let cyclic = workflow(vec![step("a", &["b.csv"], "a.csv"), step("b", &["a.csv"], "b.csv")]);
+        assert!(matches!(WorkflowGraph::build(&cyclic), Err(ExecutorError::InvalidWorkflow(e)) if e.contains("cycle")));
+        let missing = workflow(vec![step("a", &["nowhere.csv"], "a.csv")]);
+        assert!(matches!(WorkflowGraph::build(&missing), Err(ExecutorError::InvalidWorkflow(e)) if e.contains("nowhere.csv")));
+    }
+}
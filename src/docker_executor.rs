@@ -1,55 +1,632 @@
-use bollard::{Docker, container::{CreateContainerOptions, AttachContainerOptions, AttachContainerResults, RemoveContainerOptions}};
-use futures_util::StreamExt;
-use tokio::io::AsyncWriteExt;
-use uuid::Uuid;
-use tracing::debug;
-
-use crate::{error::ExecutorError, workflow::Workflow};
-
-pub(crate) async fn execute_docker_orchestrator(docker: Docker, workflow: Workflow, id: Uuid) -> Result<(), ExecutorError> {
-    let container_name = format!("DockerOrchestrator-{id}");
-    let container_options = CreateContainerOptions {name: &container_name, platform: None};
-    let start_options = bollard::container::Config {
-        image: Some("orchestrator-tester:local"),
-        attach_stdin: Some(true),
-        attach_stderr: Some(true),
-        attach_stdout: Some(true),
-        tty: Some(true),
-        open_stdin: Some(true),
+use std::{collections::{BTreeMap, BTreeSet, HashMap, VecDeque}, io::Read, path::Path, sync::{Arc, Mutex}};
+
+use async_trait::async_trait;
+
+use bollard::{Docker, container::{CreateContainerOptions, AttachContainerOptions, InspectContainerOptions, KillContainerOptions, AttachContainerResults, LogOutput, RemoveContainerOptions, StopContainerOptions, WaitContainerOptions, LogsOptions, DownloadFromContainerOptions}, volume::{CreateVolumeOptions, RemoveVolumeOptions}, service::{HostConfig, Mount, MountTypeEnum}};
+use futures_util::{stream::FuturesUnordered, StreamExt, TryStreamExt};
+use tokio::{io::AsyncWriteExt, sync::Semaphore, time::{timeout, timeout_at, Instant}};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::{config::BeamConfig, dag::WorkflowGraph, error::ExecutorError, executor::{Capabilities, Executor, Run}, format::{parse_document, Format}, policy::ImageRef, pull::{ensure_image, PullSettings}, validation::{check_images, ValidationError}, workflow::{ExecutionTask, OutputFile, RunReport, SiteLimits, StepFailurePolicy, StepReport, StepResources, StepStatus, Workflow, WorkflowSteps}};
+
+/// Where the volume shared by all steps of a run is mounted.
+const DATA_DIR: &str = "/data";
+
+/// Image of the delegate orchestrator started with `--docker-delegate`, unless `--delegate-image` is set.
+pub(crate) const DELEGATE_IMAGE: &str = "orchestrator-tester:local";
+
+/// How long a container may take to exit after being asked to stop before it is killed.
+const STOP_GRACE_SECONDS: i64 = 10;
+
+/// How a delegate orchestrator container is started. Delegates receive the workflow on stdin or, without `stdin`,
+/// in the environment variable `BK_WORKFLOW`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DelegateProfile {
+    pub image: String,
+    /// Overrides the image's `CMD`
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// Overrides the image's `ENTRYPOINT`
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    /// Additional environment entries of the form `KEY=value`
+    #[serde(default)]
+    pub env: Vec<String>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default = "enabled")]
+    pub tty: bool,
+    #[serde(default = "enabled")]
+    pub stdin: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl DelegateProfile {
+    /// The container running `workflow` (serialized) with `image`, which is the profile's image, possibly pinned.
+    fn container_config(&self, image: &str, workflow: &str) -> bollard::container::Config<String> {
+        let mut env = self.env.clone();
+        if !self.stdin {
+            env.push(format!("BK_WORKFLOW={workflow}"));
+        }
+        bollard::container::Config {
+            image: Some(image.to_string()),
+            cmd: self.command.clone(),
+            entrypoint: self.entrypoint.clone(),
+            env: Some(env),
+            working_dir: self.working_dir.clone(),
+            attach_stdin: Some(self.stdin),
+            attach_stderr: Some(true),
+            attach_stdout: Some(true),
+            tty: Some(self.tty),
+            open_stdin: Some(self.stdin),
+            stdin_once: Some(self.stdin),
+            ..Default::default()
+        }
+    }
+}
+
+/// Checks an environment entry of a delegate, from `--delegate-env` or a profile, e.g. `LOG_LEVEL=debug`.
+pub(crate) fn parse_env_entry(entry: &str) -> Result<String, String> {
+    match entry.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(entry.to_string()),
+        _ => Err(format!("{entry:?} is not of the form KEY=value")),
+    }
+}
+
+/// Reads the profiles of `--delegate-profiles`, a JSON or YAML map from executor name to `DelegateProfile`.
+pub(crate) fn load_delegate_profiles(path: &Path) -> Result<BTreeMap<String, DelegateProfile>, ExecutorError> {
+    let format = Format::from_path(path)
+        .ok_or_else(|| ExecutorError::ConfigurationError(format!("Delegate profiles {} must be a .json, .yaml or .yml file", path.display())))?;
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot read delegate profiles {}: {e}", path.display())))?;
+    let profiles: BTreeMap<String, DelegateProfile> = parse_document(&content, format)
+        .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot parse delegate profiles {}: {e}", path.display())))?;
+    for (name, profile) in &profiles {
+        for entry in &profile.env {
+            parse_env_entry(entry).map_err(|e| ExecutorError::ConfigurationError(format!("Environment entry of delegate profile {name}: {e}")))?;
+        }
+    }
+    Ok(profiles)
+}
+
+/// Hands the whole workflow to a delegate orchestrator container, started as described by its `DelegateProfile`,
+/// which writes the result body to stdout.
+#[derive(Debug)]
+pub(crate) struct DockerDelegateExecutor {
+    docker: Docker,
+    limits: SiteLimits,
+    pull: PullSettings,
+    profile: DelegateProfile,
+    /// Container of each run
+    runs: Mutex<HashMap<String, String>>,
+    /// Output of each run that has finished
+    outputs: Mutex<HashMap<String, String>>,
+}
+
+impl DockerDelegateExecutor {
+    pub fn new(config: &BeamConfig, profile: DelegateProfile) -> Result<Self, ExecutorError> {
+        Ok(DockerDelegateExecutor { docker: connect()?, limits: config.limits.clone(), pull: config.pull.clone(), profile, runs: Mutex::default(), outputs: Mutex::default() })
+    }
+}
+
+#[async_trait]
+impl Executor for DockerDelegateExecutor {
+    fn capabilities(&self) -> Capabilities {
+        // Steps are run by the delegate, which does not tell us what it supports
+        Capabilities::default()
+    }
+
+    fn check_workflow(&self, workflow: &Workflow) -> Vec<ValidationError> {
+        check_images(workflow, &self.limits.images)
+    }
+
+    async fn prepare(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        check_docker(&self.docker).await?;
+        let image = &self.profile.image;
+        self.limits.images.check(image)
+            .map_err(|reason| ExecutorError::TaskRejected(format!("Delegate image {image} is not allowed: {reason}")))?;
+        ensure_image(&self.docker, image, &self.pull).await?;
+        let image = if self.limits.images.resolve_digests { resolve_digest(&self.docker, image).await? } else { image.clone() };
+        let workflow = serde_json::to_string(&run.task.workflow).map_err(ExecutorError::UnableToParseWorkload)?;
+        let container_name = format!("DockerOrchestrator-{}-{}", run.task.context.id, run.attempt);
+        let container_options = CreateContainerOptions {name: container_name.clone(), platform: None};
+        let start_options = self.profile.container_config(&image, &workflow);
+        let id = self.docker.create_container(Some(container_options), start_options).await.map_err(|e|ExecutorError::DockerError(format!("Cannot create container {container_name}: {e}")))?.id;
+        debug!("Created container {container_name}: {id}");
+        self.runs.lock().unwrap().insert(run.name.clone(), id);
+        Ok(())
+    }
+
+    /// Feeds the workflow to the delegate and collects everything it writes to stdout; a delegate exiting with a
+    /// non-zero status fails the step with what it wrote to stderr. The container is stopped if it is still running
+    /// when the task's ttl elapses or the workflow's timeout is exceeded.
+    async fn run(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        let docker = &self.docker;
+        let task = run.task;
+        let id = self.runs.lock().unwrap().get(&run.name).cloned()
+            .ok_or_else(|| ExecutorError::DockerError(format!("Run {} was not prepared", run.name)))?;
+        let workflow_deadline = self.limits.workflow_timeout(&task.workflow).map(|timeout| Instant::now() + timeout);
+        // Attached before starting, so that the output of quickly exiting delegates is not lost
+        let attach_options = AttachContainerOptions::<String> {
+            stdout: Some(true),
+            stderr: Some(true),
+            stdin: Some(self.profile.stdin),
+            stream: Some(true),
+            ..Default::default()
+        };
+        let AttachContainerResults { mut output, mut input }=
+            docker.attach_container(&id, Some(attach_options)).await.map_err(|e|ExecutorError::DockerError(format!("Cannot attach to container {id}: {e}")))?;
+        debug!("Attached to container {:?}", id);
+        docker.start_container::<String>(&id, None).await.map_err(|e| ExecutorError::DockerError(format!("Cannot start container: {e}")))?;
+
+        if self.profile.stdin {
+            let input_instruction = serde_json::to_string(&task.workflow).map_err(ExecutorError::UnableToParseWorkload)?;
+            debug!("Attempting to send to stdin: {}", input_instruction);
+            input.write_all(input_instruction.as_bytes()).await.map_err(|e|ExecutorError::DockerError(format!("Cannot write to stdin of container {id}: {e}")))?;
+            input.write("\n".as_bytes()).await.map_err(|e|ExecutorError::DockerError(format!("Cannot write newline to stdin of container {id}: {e}")))?;
+            input.flush().await.map_err(|e|ExecutorError::DockerError(format!("Cannot flush stdin: {}", e)))?;
+            input.shutdown().await.map_err(|e|ExecutorError::DockerError(format!("Cannot close stdin: {}", e)))?;
+            debug!("Closed stream, written to stdin.");
+        }
+
+        debug!("Attempting to read from stdout");
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        let read_output = async {
+            while let Some(Ok(msg)) = output.next().await {
+                debug!("Container {id}: {msg}");
+                match msg {
+                    // With a tty, both end up in `Console`
+                    LogOutput::StdErr { .. } => stderr.push_str(&msg.to_string()),
+                    _ => stdout.push_str(&msg.to_string()),
+                }
+            }
+            let mut wait = docker.wait_container(&id, Some(WaitContainerOptions { condition: "not-running" }));
+            match wait.next().await {
+                Some(Ok(response)) => Ok(response.status_code),
+                Some(Err(bollard::errors::Error::DockerContainerWaitError { code, .. })) => Ok(code),
+                Some(Err(e)) => Err(ExecutorError::DockerError(format!("Cannot wait for container {id}: {e}"))),
+                None => Err(ExecutorError::DockerError(format!("No exit status for container {id}"))),
+            }
+        };
+        let deadline = workflow_deadline.map_or(task.context.expires_at, |deadline| deadline.min(task.context.expires_at));
+        let Ok(status_code) = timeout_at(deadline, read_output).await else {
+            let timed_out = deadline < task.context.expires_at;
+            warn!("{}, stopping container {id}", if timed_out { "Workflow timeout exceeded" } else { "Task ttl elapsed" });
+            stop_container(docker, &id).await;
+            return Err(if timed_out {
+                ExecutorError::TimedOut(format!("Delegate of run {} was stopped because the workflow's timeout was exceeded", run.name))
+            } else {
+                ExecutorError::TaskExpired(format!("Delegate of run {} was stopped because the task's ttl elapsed", run.name))
+            });
+        };
+        let status_code = status_code?;
+        debug!("EOS from stdout, delegate exited with {status_code}");
+        if status_code != 0 {
+            let logs = if stderr.is_empty() { &stdout } else { &stderr };
+            return Err(ExecutorError::StepFailed(format!("Delegate of run {} exited with status {status_code}: {logs}", run.name)));
+        }
+        self.outputs.lock().unwrap().insert(run.name.clone(), stdout);
+        Ok(())
+    }
+
+    async fn collect_outputs(&self, run: &Run<'_>) -> Result<String, ExecutorError> {
+        self.outputs.lock().unwrap().remove(&run.name)
+            .ok_or_else(|| ExecutorError::DockerError(format!("Run {} has no output", run.name)))
+    }
+
+    async fn cancel(&self, run: &Run<'_>) {
+        self.outputs.lock().unwrap().remove(&run.name);
+        let Some(id) = self.runs.lock().unwrap().remove(&run.name) else {
+            return;
+        };
+        if let Err(e) = self.docker.remove_container(&id, Some(RemoveContainerOptions {force: true, ..Default::default()})).await {
+            warn!("Cannot remove container {id}: {e}");
+        }
+        debug!("Container {id} removed");
+    }
+}
+
+/// Runs every step of the workflow as its own container in dependency order and returns a `RunReport`
+/// including the workflow's output files. Steps exchange files through a volume mounted at `/data`; a step
+/// finds its inputs in `BK_INPUTS` (comma separated paths) and must write its output to `BK_OUTPUT`.
+#[derive(Debug)]
+pub(crate) struct DockerExecutor {
+    docker: Docker,
+    limits: SiteLimits,
+    pull: PullSettings,
+    /// Shared by all runs to enforce `--max-parallel-steps`
+    step_slots: Arc<Semaphore>,
+    on_step_failure: StepFailurePolicy,
+    runs: Mutex<HashMap<String, DockerRun>>,
+}
+
+/// State of a run of the `DockerExecutor`.
+#[derive(Debug)]
+struct DockerRun {
+    /// The task with its images pinned, if digests are resolved
+    task: ExecutionTask,
+    volume: bool,
+    /// The container of each step index that has been created
+    containers: BTreeMap<usize, String>,
+    steps: Vec<StepReport>,
+}
+
+impl DockerExecutor {
+    pub fn new(config: &BeamConfig) -> Result<Self, ExecutorError> {
+        Ok(DockerExecutor {
+            docker: connect()?,
+            limits: config.limits.clone(),
+            pull: config.pull.clone(),
+            step_slots: config.step_slots.clone(),
+            on_step_failure: config.on_step_failure,
+            runs: Mutex::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl Executor for DockerExecutor {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { resource_limits: true, step_timeouts: true, parallelism: true }
+    }
+
+    fn check_workflow(&self, workflow: &Workflow) -> Vec<ValidationError> {
+        check_images(workflow, &self.limits.images)
+    }
+
+    async fn prepare(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        check_docker(&self.docker).await?;
+        // File and step names as well as images have been checked by `validate_workflow` when the task was accepted
+        WorkflowGraph::build(&run.task.workflow)?;
+        let images: BTreeSet<&String> = run.task.workflow.steps.iter().map(|step| &step.image).collect();
+        for image in images {
+            ensure_image(&self.docker, image, &self.pull).await?;
+        }
+        let mut task = run.task.clone();
+        if self.limits.images.resolve_digests {
+            for step in &mut task.workflow.steps {
+                step.image = resolve_digest(&self.docker, &step.image).await?;
+            }
+        }
+        let mut state = DockerRun { task, volume: false, containers: BTreeMap::new(), steps: Vec::new() };
+        let volume = self.docker.create_volume(CreateVolumeOptions { name: run.name.as_str(), ..Default::default() }).await;
+        state.volume = volume.is_ok();
+        self.runs.lock().unwrap().insert(run.name.clone(), state);
+        volume.map_err(|e| ExecutorError::DockerError(format!("Cannot create volume {}: {e}", run.name)))?;
+        debug!("Created volume {}", run.name);
+        Ok(())
+    }
+
+    async fn run(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        let task = self.runs.lock().unwrap().get(&run.name).map(|state| state.task.clone())
+            .ok_or_else(|| ExecutorError::DockerError(format!("Run {} was not prepared", run.name)))?;
+        let graph = WorkflowGraph::build(&task.workflow)?;
+        let workflow_deadline = self.limits.workflow_timeout(&task.workflow).map(|timeout| Instant::now() + timeout);
+        let containers = Mutex::new(BTreeMap::new());
+        let mut steps = Vec::new();
+        let result = self.run_steps(&task, &graph, &run.name, workflow_deadline, &containers, &mut steps).await;
+        if let Some(state) = self.runs.lock().unwrap().get_mut(&run.name) {
+            state.containers = containers.into_inner().unwrap();
+            state.steps = steps;
+        }
+        result
+    }
+
+    async fn collect_outputs(&self, run: &Run<'_>) -> Result<String, ExecutorError> {
+        let (task, containers, steps) = self.runs.lock().unwrap().get(&run.name)
+            .map(|state| (state.task.clone(), state.containers.clone(), state.steps.clone()))
+            .ok_or_else(|| ExecutorError::DockerError(format!("Run {} was not prepared", run.name)))?;
+        let workflow = &task.workflow;
+        let mut outputs = BTreeMap::new();
+        for file in &workflow.output {
+            let container = WorkflowGraph::producer(workflow, file)
+                .and_then(|index| containers.get(&index))
+                .ok_or_else(|| ExecutorError::InvalidWorkflow(format!("No step produces the workflow output {file}")))?;
+            outputs.insert(file.clone(), download_file(&self.docker, container, file).await?);
+        }
+        serde_json::to_string(&RunReport { steps, outputs }).map_err(ExecutorError::UnableToParseWorkload)
+    }
+
+    async fn cancel(&self, run: &Run<'_>) {
+        let Some(state) = self.runs.lock().unwrap().remove(&run.name) else {
+            return;
+        };
+        for id in state.containers.values() {
+            if let Err(e) = self.docker.remove_container(id, Some(RemoveContainerOptions {force: true, ..Default::default()})).await {
+                warn!("Cannot remove container {id}: {e}");
+            }
+        }
+        if state.volume {
+            if let Err(e) = self.docker.remove_volume(&run.name, Some(RemoveVolumeOptions { force: true })).await {
+                warn!("Cannot remove volume {}: {e}", run.name);
+            }
+        }
+    }
+}
+
+impl DockerExecutor {
+    /// Runs the steps, starting each one as soon as the steps it depends on have succeeded, within the site-wide and
+    /// the workflow's parallelism limits. A step's container is only created once it holds its slots, and is recorded
+    /// in `containers` by step index so `cancel` can clean up, which also stops the steps still running when a failure
+    /// cancels the run. A container that cannot be created fails its step like any other error.
+    async fn run_steps(&self, task: &ExecutionTask, graph: &WorkflowGraph, run: &str, workflow_deadline: Option<Instant>, containers: &Mutex<BTreeMap<usize, String>>, steps: &mut Vec<StepReport>) -> Result<(), ExecutorError> {
+        let docker = &self.docker;
+        let workflow = &task.workflow;
+        let workflow_slots = Semaphore::new(workflow.max_parallelism.unwrap_or(workflow.steps.len()).max(1));
+        let mut waiting_for: Vec<usize> = graph.dependencies.iter().map(Vec::len).collect();
+        let mut ready: VecDeque<usize> = graph.roots().collect();
+        let mut running = FuturesUnordered::new();
+        let mut in_flight = BTreeSet::new();
+        let mut failed = false;
+        let mut error = None;
+
+        loop {
+            while let Some(index) = ready.pop_front().filter(|_| !failed) {
+                let step = &workflow.steps[index];
+                in_flight.insert(index);
+                let workflow_slots = &workflow_slots;
+                running.push(async move {
+                    // The run's own limit first, so waiting steps do not hold slots other runs could use
+                    let _workflow_slot = workflow_slots.acquire().await;
+                    let _site_slot = self.step_slots.acquire().await;
+                    let id = match create_step_container(docker, step, run, &self.limits.resources).await {
+                        Ok(id) => id,
+                        Err(e) => return (index, Err(e)),
+                    };
+                    containers.lock().unwrap().insert(index, id.clone());
+                    let step_deadline = self.limits.step_timeout(step).map(|timeout| Instant::now() + timeout);
+                    let deadline = match (step_deadline, workflow_deadline) {
+                        (Some(step), Some(workflow)) => Some(step.min(workflow)),
+                        (step, workflow) => step.or(workflow),
+                    };
+                    (index, run_step(docker, task, step, &id, deadline).await)
+                });
+            }
+
+            let Some((index, result)) = running.next().await else {
+                break;
+            };
+            in_flight.remove(&index);
+            match result {
+                Ok(report) if matches!(report.status, StepStatus::Succeeded) => {
+                    steps.push(report);
+                    for dependent in &graph.dependents[index] {
+                        waiting_for[*dependent] -= 1;
+                        if waiting_for[*dependent] == 0 {
+                            ready.push_back(*dependent);
+                        }
+                    }
+                },
+                Ok(report) => {
+                    warn!("Step {} failed, applying {:?} to the other steps", report.name, self.on_step_failure);
+                    failed = true;
+                    steps.push(report);
+                },
+                Err(e) => {
+                    warn!("Step {} failed, applying {:?} to the other steps: {}", workflow.steps[index].name, self.on_step_failure, e);
+                    failed = true;
+                    error = error.or(Some(e));
+                }
+            }
+            if failed && self.on_step_failure == StepFailurePolicy::Cancel {
+                break;
+            }
+        }
+
+        if failed {
+            drop(running);
+            for index in in_flight {
+                steps.push(StepReport { name: workflow.steps[index].name.clone(), status: StepStatus::Cancelled, exit_code: None, logs: String::new() });
+            }
+            return Err(error.unwrap_or_else(|| ExecutorError::from_steps(steps)));
+        }
+        Ok(())
+    }
+}
+
+fn connect() -> Result<Docker, ExecutorError> {
+    Docker::connect_with_local_defaults().map_err(|e| ExecutorError::DockerError(format!("Cannot initialize docker: {e}")))
+}
+
+async fn check_docker(docker: &Docker) -> Result<(), ExecutorError> {
+    let version = docker.version().await.map_err(|e| ExecutorError::DockerError(format!("Cannot connect to docker: {e}")))?;
+    debug!("Docker version: {:?}", version);
+    Ok(())
+}
+
+/// Creates the step's container, limited to the step's resources or, where it requests none, the site's `limits`.
+async fn create_step_container(docker: &Docker, step: &WorkflowSteps, run: &str, limits: &StepResources) -> Result<String, ExecutorError> {
+    let container_name = format!("{run}-{}", step.name);
+    let inputs: Vec<String> = step.inputs().iter().map(|input| format!("{DATA_DIR}/{input}")).collect();
+    let mut env = step.env.clone().unwrap_or_default();
+    env.push(format!("BK_INPUTS={}", inputs.join(",")));
+    env.push(format!("BK_OUTPUT={DATA_DIR}/{}", step.output));
+    let resources = step.resources.clone().unwrap_or_default().within(limits);
+
+    let config = bollard::container::Config {
+        image: Some(step.image.clone()),
+        env: Some(env),
+        working_dir: Some(DATA_DIR.to_string()),
+        host_config: Some(HostConfig {
+            mounts: Some(vec![Mount {
+                target: Some(DATA_DIR.to_string()),
+                source: Some(run.to_string()),
+                typ: Some(MountTypeEnum::VOLUME),
+                ..Default::default()
+            }]),
+            nano_cpus: resources.cpus.map(|cpus| (cpus * 1e9) as i64),
+            memory: resources.memory.map(|memory| memory as i64),
+            // Without swap, so that the memory limit actually holds
+            memory_swap: resources.memory.map(|memory| memory as i64),
+            pids_limit: resources.pids,
+            tmpfs: resources.tmpfs.map(|size| HashMap::from([("/tmp".to_string(), format!("size={size}"))])),
+            storage_opt: resources.disk.map(|size| HashMap::from([("size".to_string(), size.to_string())])),
+            ..Default::default()
+        }),
         ..Default::default()
     };
+    let id = docker.create_container(Some(CreateContainerOptions { name: container_name.as_str(), platform: None }), config).await
+        .map_err(|e| ExecutorError::DockerError(format!("Cannot create container {container_name}: {e}")))?.id;
+    debug!("Created container {container_name} for step {}: {id}", step.name);
+    Ok(id)
+}
 
-    let id = docker.create_container(Some(container_options), start_options).await.map_err(|e|ExecutorError::DockerError(format!("Cannot create container {container_name}: {e}")))?.id;
-    docker.start_container::<String>(&id, None).await.map_err(|e| ExecutorError::DockerError(format!("Cannot start container: {e}")))?;
-    debug!("Created container: {:?}", id);
+/// Starts the step's container and waits for it to exit. The step is stopped and reported as timed out once
+/// `deadline` has passed, and stopped with an error if the task's ttl elapses first.
+async fn run_step(docker: &Docker, task: &ExecutionTask, step: &WorkflowSteps, id: &str, deadline: Option<Instant>) -> Result<StepReport, ExecutorError> {
+    info!("Starting step {} ({})", step.name, step.image);
+    docker.start_container::<String>(id, None).await.map_err(|e| ExecutorError::DockerError(format!("Cannot start container for step {}: {e}", step.name)))?;
 
-    let attach_options = AttachContainerOptions::<String> {
-        stdout: Some(true),
-        // stderr: Some(true),
-        stdin: Some(true),
-        stream: Some(true),
-        ..Default::default()
+    let wait = async {
+        let mut wait = docker.wait_container(id, Some(WaitContainerOptions { condition: "not-running" }));
+        match wait.next().await {
+            Some(Ok(response)) => Ok(response.status_code),
+            Some(Err(bollard::errors::Error::DockerContainerWaitError { code, .. })) => Ok(code),
+            Some(Err(e)) => Err(ExecutorError::DockerError(format!("Cannot wait for step {}: {e}", step.name))),
+            None => Err(ExecutorError::DockerError(format!("No exit status for step {}", step.name))),
+        }
+    };
+    let timed_out = matches!(deadline, Some(deadline) if deadline < task.context.expires_at);
+    let exit_code = match timeout_at(deadline.filter(|_| timed_out).unwrap_or(task.context.expires_at), wait).await {
+        Ok(exit_code) => Some(exit_code?),
+        Err(_) if timed_out => {
+            warn!("Timeout exceeded, stopping step {}", step.name);
+            stop_container(docker, id).await;
+            None
+        },
+        Err(_) => {
+            warn!("Task ttl elapsed, stopping step {}", step.name);
+            stop_container(docker, id).await;
+            return Err(ExecutorError::TaskExpired(format!("Step {} was stopped because the task's ttl elapsed", step.name)));
+        }
     };
-    let AttachContainerResults { mut output, mut input }=
-        docker.attach_container(&id, Some(attach_options)).await.map_err(|e|ExecutorError::DockerError(format!("Cannot attach to container {id}: {e}")))?;
-    debug!("Attached to container {:?}", id);
-
-    let input_instruction = serde_json::to_string(&workflow).map_err(|e|ExecutorError::UnableToParseWorkload(e))?;
-    debug!("Attempting to send to stdin: {}", input_instruction);
-    input.write_all(input_instruction.as_bytes()).await.map_err(|e|ExecutorError::DockerError(format!("Cannot write to stdin of container {id}: {e}")))?;
-    input.write("\n".as_bytes()).await.map_err(|e|ExecutorError::DockerError(format!("Cannot write newline to stdin of container {id}: {e}")))?;
-    input.flush().await.map_err(|e|ExecutorError::DockerError(format!("Cannot flush stdin: {}", e)))?;
-    input.shutdown().await.map_err(|e|ExecutorError::DockerError(format!("Cannot close stdin: {}", e)))?;
-    debug!("Closed stream, written to stdin.");
-
-    debug!("Attempting to read from stdout");
-    while let Some(Ok(msg)) = output.next().await {
-        print!("{msg}");
-    }
-    
-    debug!("EOS from stdout, removing container");
-    docker.remove_container(&id, Some(RemoveContainerOptions {force: true, ..Default::default()})).await.map_err(|e|ExecutorError::DockerError(format!("Cannot remove container {id}: {e}")))?;
-    debug!("Container removed");
 
-    Ok(())
+    let logs: Vec<String> = docker.logs(id, Some(LogsOptions::<String> { stdout: true, stderr: true, tail: "all".into(), ..Default::default() }))
+        .map_ok(|line| line.to_string())
+        .try_collect()
+        .await
+        .map_err(|e| ExecutorError::DockerError(format!("Cannot read logs of step {}: {e}", step.name)))?;
+    let Some(exit_code) = exit_code else {
+        return Ok(StepReport { name: step.name.clone(), status: StepStatus::TimedOut, exit_code: None, logs: logs.concat() });
+    };
+    let oom_killed = docker.inspect_container(id, None::<InspectContainerOptions>).await
+        .map_err(|e| ExecutorError::DockerError(format!("Cannot inspect container of step {}: {e}", step.name)))?
+        .state
+        .and_then(|state| state.oom_killed)
+        .unwrap_or(false);
+    let status = match (exit_code, oom_killed) {
+        (_, true) => StepStatus::OutOfMemory,
+        (0, false) => StepStatus::Succeeded,
+        _ => StepStatus::Failed,
+    };
+    if oom_killed {
+        warn!("Step {} was killed for exceeding its memory limit", step.name);
+    } else {
+        info!("Step {} exited with code {}", step.name, exit_code);
+    }
+    Ok(StepReport { name: step.name.clone(), status, exit_code: Some(exit_code), logs: logs.concat() })
+}
+
+/// Pins `image` to the digest of the local image it refers to, or to the image's id if it was not pulled from a registry.
+async fn resolve_digest(docker: &Docker, image: &str) -> Result<String, ExecutorError> {
+    let reference: ImageRef = image.parse().map_err(ExecutorError::InvalidWorkflow)?;
+    if reference.digest.is_some() {
+        return Ok(image.to_string());
+    }
+    let inspect = docker.inspect_image(image).await
+        .map_err(|e| ExecutorError::DockerError(format!("Cannot resolve digest of image {image}: {e}")))?;
+    let digest = inspect.repo_digests.iter().flatten()
+        .find(|digest| matches!(digest.parse::<ImageRef>(), Ok(candidate) if candidate.repository == reference.repository))
+        .cloned()
+        .or(inspect.id)
+        .ok_or_else(|| ExecutorError::DockerError(format!("Image {image} has no digest")))?;
+    debug!("Pinned image {image} to {digest}");
+    Ok(digest)
+}
+
+/// Asks the container to stop and kills it if it has not exited shortly after the grace period.
+async fn stop_container(docker: &Docker, id: &str) {
+    let grace = std::time::Duration::from_secs(STOP_GRACE_SECONDS as u64 + 5);
+    match timeout(grace, docker.stop_container(id, Some(StopContainerOptions { t: STOP_GRACE_SECONDS }))).await {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) => warn!("Cannot stop container {id}, killing it: {e}"),
+        Err(_) => warn!("Container {id} did not stop in time, killing it"),
+    }
+    if let Err(e) = docker.kill_container(id, Some(KillContainerOptions { signal: "SIGKILL" })).await {
+        warn!("Cannot kill container {id}: {e}");
+    }
+}
+
+/// Copies a file from the shared volume out of a (stopped) step container.
+async fn download_file(docker: &Docker, container: &str, file: &str) -> Result<OutputFile, ExecutorError> {
+    let path = format!("{DATA_DIR}/{file}");
+    let archive: Vec<u8> = docker.download_from_container(container, Some(DownloadFromContainerOptions { path: path.as_str() }))
+        .map_ok(|chunk| chunk.to_vec())
+        .try_concat()
+        .await
+        .map_err(|e| ExecutorError::DockerError(format!("Cannot download output {file}: {e}")))?;
+    let mut archive = tar::Archive::new(archive.as_slice());
+    let entries = archive.entries().map_err(|e| ExecutorError::DockerError(format!("Cannot read archive of output {file}: {e}")))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| ExecutorError::DockerError(format!("Cannot read archive of output {file}: {e}")))?;
+        if entry.header().entry_type().is_file() {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).map_err(|e| ExecutorError::DockerError(format!("Cannot read output {file}: {e}")))?;
+            return Ok(OutputFile::from(content));
+        }
+    }
+    Err(ExecutorError::StepFailed(format!("Output {file} was not created")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    const PROFILES: &str = "
+orchestrator-v2:
+  image: registry.example.de/orchestrator:2.0
+  entrypoint: [/bin/orchestrate]
+  command: [--from-env]
+  env: [LOG_LEVEL=debug]
+  working_dir: /work
+  tty: false
+  stdin: false
+orchestrator-v1:
+  image: orchestrator-tester:local
+";
+
+    #[test]
+    fn loads_delegate_profiles() {
+        let dir = TempDir::new("profiles");
+        let path = dir.join("profiles.yaml");
+        std::fs::write(&path, PROFILES).unwrap();
+        let profiles = load_delegate_profiles(&path);
+        std::fs::write(&path, PROFILES.replace("LOG_LEVEL=debug", "LOG_LEVEL")).unwrap();
+        let invalid = load_delegate_profiles(&path);
+
+        let profiles = profiles.unwrap();
+        let v1 = &profiles["orchestrator-v1"];
+        assert!(v1.tty && v1.stdin && v1.command.is_none());
+        let config = v1.container_config("orchestrator-tester:local", "{}");
+        assert_eq!(config.env, Some(Vec::new()));
+        assert_eq!(config.open_stdin, Some(true));
+
+        let config = profiles["orchestrator-v2"].container_config("registry.example.de/orchestrator@sha256:abc", r#"{"steps":[]}"#);
+        assert_eq!(config.image.as_deref(), Some("registry.example.de/orchestrator@sha256:abc"));
+        assert_eq!(config.entrypoint, Some(vec!["/bin/orchestrate".to_string()]));
+        assert_eq!(config.cmd, Some(vec!["--from-env".to_string()]));
+        assert_eq!(config.env, Some(vec!["LOG_LEVEL=debug".to_string(), r#"BK_WORKFLOW={"steps":[]}"#.to_string()]));
+        assert_eq!(config.working_dir.as_deref(), Some("/work"));
+        assert_eq!((config.tty, config.open_stdin, config.attach_stdin), (Some(false), Some(false), Some(false)));
+        assert!(matches!(invalid, Err(ExecutorError::ConfigurationError(_))));
+        assert_eq!(parse_env_entry("LOG_LEVEL=debug"), Ok("LOG_LEVEL=debug".into()));
+        assert!(parse_env_entry("=debug").is_err());
+    }
 }
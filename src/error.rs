@@ -1,23 +1,153 @@
+use serde::Serialize;
 use thiserror::Error;
 
+use crate::validation::ValidationError;
+use crate::workflow::{StepReport, StepStatus};
+
 #[derive(Error, Debug)]
 pub enum ExecutorError {
-    #[error("Unable to retrieve tasks from Beam")]
+    #[error("Unable to retrieve tasks from Beam: {0}")]
     UnableToRetrieveTasks(reqwest::Error),
-    #[error("Unable to parse tasks from Beam")]
+    #[error("Unable to parse tasks from Beam: {0}")]
     UnableToParseTasks(reqwest::Error),
-    #[error("Unable to parse workload")]
+    #[error("Unable to parse workload: {0}")]
     UnableToParseWorkload(serde_json::Error),
-    #[error("Unable to answer task")]
+    #[error("Unable to answer task: {0}")]
     UnableToAnswerTask(reqwest::Error),
-    #[error("Unable to set proxy settings")]
+    #[error("Task streaming not supported by the Beam proxy: {0}")]
+    StreamingUnsupported(String),
+    #[error("Task stream interrupted: {0}")]
+    StreamingFailed(String),
+    #[error("Unable to set proxy settings: {0}")]
     InvalidProxyConfig(reqwest::Error),
-    #[error("Configuration error")]
+    #[error("Configuration error: {0}")]
     ConfigurationError(String),
-    #[error("Invalid BeamID")]
+    #[error("Invalid BeamID: {0}")]
     InvalidBeamId(String),
-    #[error("Parsing error")]
+    #[error("Parsing error: {0}")]
     ParsingError(String),
-    #[error("Docker API error")]
+    #[error("Docker API error: {0}")]
     DockerError(String),
-}
\ No newline at end of file
+    #[error("Image pull failed: {0}")]
+    ImagePullFailed(String),
+    #[error("Slurm error: {0}")]
+    SlurmError(String),
+    #[error("Executor not implemented: {0}")]
+    NotImplemented(String),
+    #[error("Task expired: {0}")]
+    TaskExpired(String),
+    #[error("Task rejected: {0}")]
+    TaskRejected(String),
+    #[error("Task interrupted: {0}")]
+    TaskInterrupted(String),
+    #[error("Invalid workflow: {0}")]
+    InvalidWorkflow(String),
+    #[error("Invalid workflow: {}", ValidationErrors(.0))]
+    ValidationFailed(Vec<ValidationError>),
+    #[error("Timeout exceeded: {0}")]
+    TimedOut(String),
+    #[error("Workflow step failed: {0}")]
+    StepFailed(String),
+    #[error("Workflow step ran out of memory: {0}")]
+    OutOfMemory(String),
+}
+
+/// Body of a failed `BeamResult`, so requesters can tell failure causes apart.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    pub error: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<ValidationError>,
+}
+
+/// Joins validation errors into a single human readable message.
+struct ValidationErrors<'a>(&'a [ValidationError]);
+
+impl std::fmt::Display for ValidationErrors<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a single failed execution attempt.
+#[derive(Debug, Clone, Serialize)]
+pub struct AttemptReport {
+    pub attempt: usize,
+    #[serde(flatten)]
+    pub error: ErrorReport,
+}
+
+/// Body of the final `PermFailed` result after all attempts have been used up.
+#[derive(Debug, Serialize)]
+pub struct FailureReport {
+    #[serde(flatten)]
+    pub error: ErrorReport,
+    pub attempts: Vec<AttemptReport>,
+}
+
+impl ExecutorError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ExecutorError::UnableToRetrieveTasks(_) => "UnableToRetrieveTasks",
+            ExecutorError::UnableToParseTasks(_) => "UnableToParseTasks",
+            ExecutorError::UnableToParseWorkload(_) => "UnableToParseWorkload",
+            ExecutorError::UnableToAnswerTask(_) => "UnableToAnswerTask",
+            ExecutorError::StreamingUnsupported(_) => "StreamingUnsupported",
+            ExecutorError::StreamingFailed(_) => "StreamingFailed",
+            ExecutorError::InvalidProxyConfig(_) => "InvalidProxyConfig",
+            ExecutorError::ConfigurationError(_) => "ConfigurationError",
+            ExecutorError::InvalidBeamId(_) => "InvalidBeamId",
+            ExecutorError::ParsingError(_) => "ParsingError",
+            ExecutorError::DockerError(_) => "DockerError",
+            ExecutorError::SlurmError(_) => "SlurmError",
+            ExecutorError::ImagePullFailed(_) => "ImagePullFailed",
+            ExecutorError::NotImplemented(_) => "NotImplemented",
+            ExecutorError::TaskExpired(_) => "TaskExpired",
+            ExecutorError::TaskRejected(_) => "TaskRejected",
+            ExecutorError::TaskInterrupted(_) => "TaskInterrupted",
+            ExecutorError::InvalidWorkflow(_) => "InvalidWorkflow",
+            ExecutorError::ValidationFailed(_) => "ValidationFailed",
+            ExecutorError::TimedOut(_) => "TimedOut",
+            ExecutorError::StepFailed(_) => "StepFailed",
+            ExecutorError::OutOfMemory(_) => "OutOfMemory",
+        }
+    }
+
+    /// Error for a run whose steps did not all succeed. Steps that timed out or ran out of memory would do so again,
+    /// so they are reported as `TimedOut` and `OutOfMemory` rather than the retryable `StepFailed`.
+    pub(crate) fn from_steps(steps: &[StepReport]) -> Self {
+        let reports = serde_json::to_string(steps).unwrap_or_default();
+        if steps.iter().any(|step| step.status == StepStatus::TimedOut) {
+            ExecutorError::TimedOut(reports)
+        } else if steps.iter().any(|step| step.status == StepStatus::OutOfMemory) {
+            ExecutorError::OutOfMemory(reports)
+        } else {
+            ExecutorError::StepFailed(reports)
+        }
+    }
+
+    /// Whether running the task again may succeed, e.g. after a transient Docker hiccup.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ExecutorError::DockerError(_) | ExecutorError::ImagePullFailed(_) | ExecutorError::SlurmError(_) | ExecutorError::StepFailed(_))
+    }
+
+    pub fn report(&self) -> ErrorReport {
+        let problems = match self {
+            ExecutorError::ValidationFailed(problems) => problems.clone(),
+            _ => Vec::new(),
+        };
+        ErrorReport { error: self.kind(), message: self.to_string(), problems }
+    }
+
+    /// Serialized `ErrorReport`, ready to be used as a `BeamResult` body.
+    pub fn to_result_body(&self) -> String {
+        serde_json::to_string(&self.report()).unwrap_or_else(|_| self.to_string())
+    }
+}
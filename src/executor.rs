@@ -0,0 +1,165 @@
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use tracing::debug;
+
+use crate::{config::BeamConfig, docker_executor::{DockerDelegateExecutor, DockerExecutor}, error::ExecutorError, hpc_executor::SlurmExecutor, local_executor::LocalExecutor, validation::ValidationError, workflow::{ExecutionTask, Workflow}};
+
+/// Workflow features an executor honours. Workflows using other features are refused when they are accepted.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Capabilities {
+    /// `WorkflowSteps.resources`
+    pub resource_limits: bool,
+    /// `WorkflowSteps.timeout`; workflow timeouts and the task's ttl are enforced by every executor
+    pub step_timeouts: bool,
+    /// `Workflow.max_parallelism`
+    pub parallelism: bool,
+}
+
+impl Capabilities {
+    /// Names of the features `workflow` uses but the executor does not support.
+    pub fn unsupported(&self, workflow: &Workflow) -> Vec<&'static str> {
+        let mut unsupported = Vec::new();
+        if !self.resource_limits && workflow.steps.iter().any(|step| step.resources.is_some()) {
+            unsupported.push("resources");
+        }
+        if !self.step_timeouts && workflow.steps.iter().any(|step| step.timeout.is_some()) {
+            unsupported.push("step timeout");
+        }
+        if !self.parallelism && workflow.max_parallelism.is_some() {
+            unsupported.push("max_parallelism");
+        }
+        unsupported
+    }
+}
+
+/// One attempt at executing a task.
+#[derive(Debug)]
+pub(crate) struct Run<'a> {
+    /// Unique per task and attempt, e.g. to name containers or directories after
+    pub name: String,
+    pub task: &'a ExecutionTask,
+    pub attempt: usize,
+}
+
+/// A backend that runs workflows. Executors keep the state of their runs themselves, keyed by `Run.name`.
+#[async_trait]
+pub(crate) trait Executor: Debug + Send + Sync {
+    fn capabilities(&self) -> Capabilities;
+
+    /// Problems specific to this executor with running `workflow`, reported along with the site's validation.
+    fn check_workflow(&self, _workflow: &Workflow) -> Vec<ValidationError> {
+        Vec::new()
+    }
+
+    /// Sets up everything the run needs, before anything is started.
+    async fn prepare(&self, run: &Run<'_>) -> Result<(), ExecutorError>;
+
+    /// Runs the workflow until it has finished or failed.
+    async fn run(&self, run: &Run<'_>) -> Result<(), ExecutorError>;
+
+    /// Returns the result body of a finished run.
+    async fn collect_outputs(&self, run: &Run<'_>) -> Result<String, ExecutorError>;
+
+    /// Stops whatever is still running and releases the run's resources. Called after every run, including failed ones.
+    async fn cancel(&self, run: &Run<'_>);
+}
+
+/// Runs one attempt of the task on `executor` and returns its result body.
+pub(crate) async fn execute(executor: &dyn Executor, task: &ExecutionTask, attempt: usize) -> Result<String, ExecutorError> {
+    let run = Run { name: format!("bk-orchestrator-{}-{attempt}", task.context.id), task, attempt };
+    let result = async {
+        executor.prepare(&run).await?;
+        executor.run(&run).await?;
+        executor.collect_outputs(&run).await
+    }.await;
+    debug!("Cleaning up run {}", run.name);
+    executor.cancel(&run).await;
+    result
+}
+
+/// Names of the executors that are not configured through `--delegate-profiles`.
+const BUILT_IN: [&str; 3] = ["DockerExecutor", "HPCExecutor", "LocalExecutor"];
+
+/// The executors configured at this site, by the name tasks use in `ExecutorInfo.name`.
+#[derive(Debug, Default)]
+pub(crate) struct ExecutorRegistry {
+    executors: BTreeMap<String, Arc<dyn Executor>>,
+}
+
+impl ExecutorRegistry {
+    /// Creates the executors named in `--executors`, which are either built in or profiles of `--delegate-profiles`.
+    pub fn load(names: &[String], config: &BeamConfig) -> Result<Self, ExecutorError> {
+        if let Some(name) = config.delegate_profiles.keys().find(|name| BUILT_IN.contains(&name.as_str())) {
+            return Err(ExecutorError::ConfigurationError(format!("Delegate profile {name} has the name of a built-in executor")));
+        }
+        let mut registry = ExecutorRegistry::default();
+        for name in names {
+            let executor: Arc<dyn Executor> = match name.as_str() {
+                "DockerExecutor" if config.docker_delegate => Arc::new(DockerDelegateExecutor::new(config, config.delegate.clone())?),
+                "DockerExecutor" => Arc::new(DockerExecutor::new(config)?),
+                "HPCExecutor" => Arc::new(SlurmExecutor::new(config)?),
+                "LocalExecutor" => Arc::new(LocalExecutor::new(config)?),
+                _ => match config.delegate_profiles.get(name) {
+                    Some(profile) => Arc::new(DockerDelegateExecutor::new(config, profile.clone())?),
+                    None => return Err(ExecutorError::ConfigurationError(format!("Unknown executor {name}, known executors are: {} and the profiles in --delegate-profiles", BUILT_IN.join(", ")))),
+                },
+            };
+            registry.register(name, executor);
+        }
+        Ok(registry)
+    }
+
+    pub fn register(&mut self, name: &str, executor: Arc<dyn Executor>) {
+        self.executors.insert(name.to_string(), executor);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Executor>> {
+        self.executors.get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.executors.keys().map(String::as_str).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{catalog::Catalog, docker_executor::DelegateProfile, test_support::{test_config, test_task, FailingExecutor, WORKFLOW}};
+
+    #[tokio::test]
+    async fn failed_runs_are_cleaned_up() {
+        let task = ExecutionTask::parse(test_task(WORKFLOW, Duration::from_secs(10)), &Catalog::load(None, true).unwrap()).unwrap();
+        let executor = FailingExecutor::default();
+        assert!(matches!(execute(&executor, &task, 2).await, Err(ExecutorError::StepFailed(_))));
+        assert_eq!(*executor.calls.lock().unwrap(), vec![format!("prepare bk-orchestrator-{}-2", task.context.id), "run".into(), "cancel".into()]);
+    }
+
+    #[test]
+    fn reports_unsupported_features() {
+        let mut task = ExecutionTask::parse(test_task(WORKFLOW, Duration::from_secs(10)), &Catalog::load(None, true).unwrap()).unwrap();
+        assert!(Capabilities::default().unsupported(&task.workflow).is_empty());
+        task.workflow.max_parallelism = Some(2);
+        task.workflow.steps[0].timeout = Some(Duration::from_secs(60));
+        assert_eq!(Capabilities::default().unsupported(&task.workflow), vec!["step timeout", "max_parallelism"]);
+        assert!(Capabilities { resource_limits: true, step_timeouts: true, parallelism: true }.unsupported(&task.workflow).is_empty());
+    }
+
+    #[test]
+    fn profiles_coexist_with_built_in_executors() {
+        let mut config = test_config("http://localhost/");
+        let profile = |image: &str| DelegateProfile { image: image.into(), ..config.delegate.clone() };
+        config.delegate_profiles = BTreeMap::from([("orchestrator-v1".into(), profile("orchestrator:1")), ("orchestrator-v2".into(), profile("orchestrator:2"))]);
+        let names = ["DockerExecutor", "orchestrator-v1", "orchestrator-v2"].map(String::from);
+        let registry = ExecutorRegistry::load(&names, &config).unwrap();
+        assert_eq!(registry.names(), vec!["DockerExecutor", "orchestrator-v1", "orchestrator-v2"]);
+        assert!(format!("{:?}", registry.get("orchestrator-v2").unwrap()).contains("orchestrator:2"));
+        assert!(ExecutorRegistry::load(&["orchestrator-v3".into()], &config).is_err());
+
+        config.delegate_profiles.insert("DockerExecutor".into(), profile("orchestrator:3"));
+        assert!(ExecutorRegistry::load(&names, &config).is_err());
+    }
+}
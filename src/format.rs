@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+
+/// Formats workflow documents can be written in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    Yaml,
+}
+
+impl Format {
+    /// JSON documents are objects, anything else is taken to be YAML. An object that is not valid JSON but valid YAML
+    /// is a YAML flow mapping such as `{executor: DockerExecutor}`; if it is neither, errors are reported for JSON.
+    pub fn detect(text: &str) -> Format {
+        let is_json = || serde_json::from_str::<IgnoredAny>(text).is_ok();
+        let is_yaml = || serde_yaml::from_str::<IgnoredAny>(text).is_ok();
+        if text.trim_start().starts_with('{') && (is_json() || !is_yaml()) {
+            Format::Json
+        } else {
+            Format::Yaml
+        }
+    }
+
+    /// Reads a format hint from a task's metadata: either just `json` or `yaml`, or a JSON object with a `format` field.
+    pub fn from_metadata(metadata: &str) -> Option<Format> {
+        let hint = match serde_json::from_str::<serde_json::Value>(metadata) {
+            Ok(serde_json::Value::Object(fields)) => fields.get("format")?.as_str()?.to_string(),
+            _ => metadata.trim().to_string(),
+        };
+        Format::from_name(&hint)
+    }
+
+    pub fn from_path(path: &Path) -> Option<Format> {
+        Format::from_name(path.extension()?.to_str()?)
+    }
+
+    fn from_name(name: &str) -> Option<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// Parses `text` in the given format. Errors name the line and column where parsing failed, if known.
+pub(crate) fn parse_document<T: DeserializeOwned>(text: &str, format: Format) -> Result<T, String> {
+    match format {
+        Format::Json => serde_json::from_str(text).map_err(|e| {
+            let message = e.to_string();
+            // serde_json appends the position to its messages
+            let message = message.split(" at line ").next().unwrap_or_default();
+            match e.line() {
+                0 => format!("invalid JSON: {message}"),
+                line => format!("invalid JSON at line {line}, column {}: {message}", e.column()),
+            }
+        }),
+        Format::Yaml => serde_yaml::from_str(text).map_err(|e| {
+            let message = e.to_string();
+            let message = message.split(" at line ").next().unwrap_or_default();
+            match e.location() {
+                Some(location) => format!("invalid YAML at line {}, column {}: {message}", location.line(), location.column()),
+                None => format!("invalid YAML: {message}"),
+            }
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn detects_formats() {
+        assert_eq!(Format::detect("  {\"a\": 1}"), Format::Json);
+        assert_eq!(Format::detect("a: 1"), Format::Yaml);
+        assert_eq!(Format::detect("{a: 1, b: [x, y]}"), Format::Yaml);
+        assert_eq!(Format::detect("{\"a\": 1"), Format::Json);
+        assert_eq!(Format::from_metadata("YAML"), Some(Format::Yaml));
+        assert_eq!(Format::from_metadata(r#"{"format":"json","other":1}"#), Some(Format::Json));
+        assert_eq!(Format::from_metadata("unused"), None);
+        assert_eq!(Format::from_path(Path::new("catalog/count.yml")), Some(Format::Yaml));
+        assert_eq!(Format::from_path(Path::new("README.md")), None);
+    }
+
+    #[test]
+    fn errors_include_position() {
+        let json = parse_document::<BTreeMap<String, u32>>("{\n  \"a\": \"x\"\n}", Format::Json).unwrap_err();
+        assert!(json.starts_with("invalid JSON at line 2, column 10: invalid type"), "{json}");
+        let yaml = parse_document::<BTreeMap<String, u32>>("a: 1\nb: x\n", Format::Yaml).unwrap_err();
+        assert!(yaml.starts_with("invalid YAML at line 2, column 4: "), "{yaml}");
+    }
+}
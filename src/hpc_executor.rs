@@ -0,0 +1,446 @@
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}, process::Stdio, sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{fs, process::Command, time::{sleep, Instant}};
+use tracing::{debug, info, warn};
+
+use crate::{config::BeamConfig, dag::WorkflowGraph, error::ExecutorError, executor::{Capabilities, Executor, Run}, validation::{check_images, ValidationError}, workflow::{ExecutionTask, OutputFile, RunReport, SiteLimits, StepFailurePolicy, StepReport, StepStatus, Workflow, WorkflowSteps}};
+
+/// Where the run's data directory is mounted in the steps' containers, as with the Docker executor
+const DATA_DIR: &str = "/data";
+
+/// Configuration of the Slurm cluster `HPCExecutor` submits to.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SlurmSettings {
+    /// Directory shared with the compute nodes; every run gets its own subdirectory
+    pub work_dir: Option<PathBuf>,
+    /// Directory containing `sbatch`, `sacct` and `scancel`; looked up in `PATH` if unset
+    pub bin_dir: Option<PathBuf>,
+    pub partition: Option<String>,
+    pub poll_interval: Duration,
+    /// Command running the steps' images on the compute nodes, e.g. `apptainer`
+    pub container_runtime: String,
+}
+
+/// Runs every step of the workflow as a Slurm batch job. Jobs depend on the jobs producing their inputs, so Slurm
+/// starts them in order; the orchestrator only polls `sacct` for their state. Steps exchange files through the
+/// run's directory in `--slurm-work-dir`, which is mounted at `/data` like the Docker executor's volume.
+#[derive(Debug)]
+pub(crate) struct SlurmExecutor {
+    settings: SlurmSettings,
+    work_dir: PathBuf,
+    limits: SiteLimits,
+    on_step_failure: StepFailurePolicy,
+    runs: Mutex<HashMap<String, SlurmRun>>,
+}
+
+/// State of a run of the `SlurmExecutor`.
+#[derive(Debug)]
+struct SlurmRun {
+    task: ExecutionTask,
+    dir: PathBuf,
+    /// Jobs that have been submitted but not finished
+    active: Vec<String>,
+    steps: Vec<StepReport>,
+}
+
+/// State of a job as reported by `sacct`.
+#[derive(Debug, PartialEq)]
+enum JobState {
+    Pending,
+    Running,
+    Finished { status: StepStatus, exit_code: Option<i64> },
+}
+
+impl JobState {
+    /// Reads `sacct`'s `State` and `ExitCode` columns, e.g. `CANCELLED by 1000` and `0:15`.
+    fn parse(state: &str, exit_code: &str) -> JobState {
+        let exit_code = exit_code.split(':').next().and_then(|code| code.parse().ok());
+        let finished = |status| JobState::Finished { status, exit_code };
+        match state.split_whitespace().next().unwrap_or_default() {
+            "PENDING" | "REQUEUED" | "REQUEUE_HOLD" | "REQUEUE_FED" => JobState::Pending,
+            "COMPLETED" => finished(StepStatus::Succeeded),
+            "TIMEOUT" | "DEADLINE" => finished(StepStatus::TimedOut),
+            "OUT_OF_MEMORY" => finished(StepStatus::OutOfMemory),
+            "CANCELLED" => finished(StepStatus::Cancelled),
+            "FAILED" | "NODE_FAIL" | "BOOT_FAIL" | "PREEMPTED" => finished(StepStatus::Failed),
+            // RUNNING, COMPLETING, SUSPENDED and whatever else is still in progress
+            _ => JobState::Running,
+        }
+    }
+}
+
+impl SlurmExecutor {
+    pub fn new(config: &BeamConfig) -> Result<Self, ExecutorError> {
+        let work_dir = config.slurm.work_dir.clone()
+            .ok_or_else(|| ExecutorError::ConfigurationError("HPCExecutor requires --slurm-work-dir".into()))?;
+        Ok(SlurmExecutor {
+            settings: config.slurm.clone(),
+            work_dir,
+            limits: config.limits.clone(),
+            on_step_failure: config.on_step_failure,
+            runs: Mutex::default(),
+        })
+    }
+
+    /// Runs one of the Slurm commands and returns its stdout.
+    async fn slurm(&self, command: &str, args: &[String]) -> Result<String, ExecutorError> {
+        let program = match &self.settings.bin_dir {
+            Some(dir) => dir.join(command),
+            None => PathBuf::from(command),
+        };
+        let output = Command::new(&program).args(args).stdin(Stdio::null()).output().await
+            .map_err(|e| ExecutorError::SlurmError(format!("Cannot run {command}: {e}")))?;
+        if !output.status.success() {
+            return Err(ExecutorError::SlurmError(format!("{command} failed with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The batch script of a step, running its image with the run's data directory mounted at `/data`.
+    fn batch_script(&self, step: &WorkflowSteps, data: &Path) -> String {
+        let inputs: Vec<String> = step.inputs().iter().map(|input| format!("{DATA_DIR}/{input}")).collect();
+        let mut env = step.env.clone().unwrap_or_default();
+        env.push(format!("BK_INPUTS={}", inputs.join(",")));
+        env.push(format!("BK_OUTPUT={DATA_DIR}/{}", step.output));
+        let mut script = String::from("#!/bin/sh\n");
+        for entry in &env {
+            script.push_str(&format!("export {}\n", shell_quote(entry)));
+        }
+        script.push_str(&format!(
+            "exec {} run --bind {} --pwd {DATA_DIR} {}\n",
+            shell_quote(&self.settings.container_runtime),
+            shell_quote(&format!("{}:{DATA_DIR}", data.display())),
+            shell_quote(&format!("docker://{}", step.image)),
+        ));
+        script
+    }
+
+    /// Submits the step's job after the jobs of the steps it depends on and returns its id.
+    async fn submit(&self, run: &str, dir: &Path, step: &WorkflowSteps, dependencies: &[&String]) -> Result<String, ExecutorError> {
+        let mut args = vec![
+            "--parsable".to_string(),
+            format!("--job-name={run}-{}", step.name),
+            format!("--chdir={}", dir.join("data").display()),
+            format!("--output={}", dir.join("jobs").join(format!("{}.log", step.name)).display()),
+        ];
+        if let Some(partition) = &self.settings.partition {
+            args.push(format!("--partition={partition}"));
+        }
+        if let Some(timeout) = self.limits.step_timeout(step) {
+            // minutes:seconds, rounded up to whole seconds
+            let seconds = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+            args.push(format!("--time={}:{:02}", seconds / 60, seconds % 60));
+        }
+        if !dependencies.is_empty() {
+            let ids: Vec<&str> = dependencies.iter().map(|id| id.as_str()).collect();
+            args.push(format!("--dependency=afterok:{}", ids.join(":")));
+            // Jobs whose dependencies failed are cancelled instead of pending forever
+            args.push("--kill-on-invalid-dep=yes".into());
+        }
+        args.push(dir.join("jobs").join(format!("{}.sh", step.name)).display().to_string());
+        let stdout = self.slurm("sbatch", &args).await?;
+        // --parsable prints the job id, followed by ";cluster" on multi-cluster setups
+        let id = stdout.trim().split(';').next().unwrap_or_default().to_string();
+        if id.is_empty() {
+            return Err(ExecutorError::SlurmError(format!("sbatch printed no job id for step {}", step.name)));
+        }
+        info!("Submitted step {} as job {id}", step.name);
+        Ok(id)
+    }
+
+    /// Asks `sacct` for the state of the given jobs. Jobs it does not know yet are left out.
+    async fn job_states(&self, ids: &[String]) -> Result<HashMap<String, JobState>, ExecutorError> {
+        let args = ["--noheader", "--parsable2", "--allocations", "--format=JobID,State,ExitCode", &format!("--jobs={}", ids.join(","))].map(String::from);
+        let stdout = self.slurm("sacct", &args).await?;
+        let mut states = HashMap::new();
+        for line in stdout.lines() {
+            let mut columns = line.split('|');
+            if let (Some(id), Some(state), Some(exit_code)) = (columns.next(), columns.next(), columns.next()) {
+                if ids.iter().any(|known| known == id) {
+                    states.insert(id.to_string(), JobState::parse(state, exit_code));
+                }
+            }
+        }
+        Ok(states)
+    }
+
+    async fn cancel_jobs(&self, ids: &[String]) {
+        if ids.is_empty() {
+            return;
+        }
+        if let Err(e) = self.slurm("scancel", ids).await {
+            warn!("Cannot cancel jobs {}: {e}", ids.join(", "));
+        }
+    }
+
+    /// Submits all steps and polls their jobs until every one of them has finished. The jobs still active are
+    /// recorded in `active`, so `cancel` can clean up after failures.
+    async fn run_jobs(&self, task: &ExecutionTask, run: &str, dir: &Path, workflow_deadline: Option<Instant>, active: &mut Vec<String>, steps: &mut Vec<StepReport>) -> Result<(), ExecutorError> {
+        let workflow = &task.workflow;
+        let graph = WorkflowGraph::build(workflow)?;
+        let mut jobs: BTreeMap<usize, String> = BTreeMap::new();
+        for index in &graph.order {
+            let dependencies: Vec<&String> = graph.dependencies[*index].iter().filter_map(|dependency| jobs.get(dependency)).collect();
+            let id = self.submit(run, dir, &workflow.steps[*index], &dependencies).await?;
+            active.push(id.clone());
+            jobs.insert(*index, id);
+        }
+
+        let deadline = workflow_deadline.map_or(task.context.expires_at, |deadline| deadline.min(task.context.expires_at));
+        let mut failed = false;
+        while !active.is_empty() {
+            let states = self.job_states(active).await?;
+            for (index, id) in &jobs {
+                let Some(JobState::Finished { status, exit_code }) = states.get(id) else {
+                    continue;
+                };
+                active.retain(|active| active != id);
+                let step = &workflow.steps[*index];
+                let logs = fs::read(dir.join("jobs").join(format!("{}.log", step.name))).await.unwrap_or_default();
+                steps.push(StepReport { name: step.name.clone(), status: status.clone(), exit_code: *exit_code, logs: String::from_utf8_lossy(&logs).into_owned() });
+                if !matches!(status, StepStatus::Succeeded) && !failed {
+                    warn!("Step {} failed, applying {:?} to the other steps", step.name, self.on_step_failure);
+                    failed = true;
+                    let cancelled: Vec<String> = match self.on_step_failure {
+                        StepFailurePolicy::Cancel => active.iter().filter(|id| !matches!(states.get(*id), Some(JobState::Finished { .. }))).cloned().collect(),
+                        StepFailurePolicy::Drain => active.iter().filter(|id| matches!(states.get(*id), None | Some(JobState::Pending))).cloned().collect(),
+                    };
+                    self.cancel_jobs(&cancelled).await;
+                }
+            }
+            if active.is_empty() {
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                let timed_out = deadline < task.context.expires_at;
+                warn!("{}, cancelling the jobs of run {run}", if timed_out { "Workflow timeout exceeded" } else { "Task ttl elapsed" });
+                self.cancel_jobs(active).await;
+                active.clear();
+                return Err(if timed_out {
+                    ExecutorError::TimedOut(format!("Jobs of run {run} were cancelled because the workflow's timeout was exceeded"))
+                } else {
+                    ExecutorError::TaskExpired(format!("Jobs of run {run} were cancelled because the task's ttl elapsed"))
+                });
+            }
+            sleep(self.settings.poll_interval.min(deadline - now)).await;
+        }
+        if failed {
+            return Err(ExecutorError::from_steps(steps));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Executor for SlurmExecutor {
+    fn capabilities(&self) -> Capabilities {
+        // Slurm schedules the jobs itself, and the limits it enforces differ from the Docker executor's
+        Capabilities { resource_limits: false, step_timeouts: true, parallelism: false }
+    }
+
+    fn check_workflow(&self, workflow: &Workflow) -> Vec<ValidationError> {
+        // The steps' images are run with the container runtime
+        check_images(workflow, &self.limits.images)
+    }
+
+    async fn prepare(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        WorkflowGraph::build(&run.task.workflow)?;
+        let dir = self.work_dir.join(&run.name);
+        let io_error = |e: std::io::Error| ExecutorError::SlurmError(format!("Cannot prepare directory {}: {e}", dir.display()));
+        fs::create_dir_all(dir.join("data")).await.map_err(io_error)?;
+        fs::create_dir_all(dir.join("jobs")).await.map_err(io_error)?;
+        let state = SlurmRun { task: run.task.clone(), dir: dir.clone(), active: Vec::new(), steps: Vec::new() };
+        self.runs.lock().unwrap().insert(run.name.clone(), state);
+        for step in &run.task.workflow.steps {
+            let script = self.batch_script(step, &dir.join("data"));
+            fs::write(dir.join("jobs").join(format!("{}.sh", step.name)), script).await.map_err(io_error)?;
+        }
+        debug!("Prepared directory {}", dir.display());
+        Ok(())
+    }
+
+    async fn run(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        let (task, dir) = self.runs.lock().unwrap().get(&run.name).map(|state| (state.task.clone(), state.dir.clone()))
+            .ok_or_else(|| ExecutorError::SlurmError(format!("Run {} was not prepared", run.name)))?;
+        let workflow_deadline = self.limits.workflow_timeout(&task.workflow).map(|timeout| Instant::now() + timeout);
+        let mut active = Vec::new();
+        let mut steps = Vec::new();
+        let result = self.run_jobs(&task, &run.name, &dir, workflow_deadline, &mut active, &mut steps).await;
+        if let Some(state) = self.runs.lock().unwrap().get_mut(&run.name) {
+            state.active = active;
+            state.steps = steps;
+        }
+        result
+    }
+
+    async fn collect_outputs(&self, run: &Run<'_>) -> Result<String, ExecutorError> {
+        let (task, dir, steps) = self.runs.lock().unwrap().get(&run.name)
+            .map(|state| (state.task.clone(), state.dir.clone(), state.steps.clone()))
+            .ok_or_else(|| ExecutorError::SlurmError(format!("Run {} was not prepared", run.name)))?;
+        let mut outputs = BTreeMap::new();
+        for file in &task.workflow.output {
+            let content = fs::read(dir.join("data").join(file)).await
+                .map_err(|e| ExecutorError::StepFailed(format!("Output {file} was not created: {e}")))?;
+            outputs.insert(file.clone(), OutputFile::from(content));
+        }
+        serde_json::to_string(&RunReport { steps, outputs }).map_err(ExecutorError::UnableToParseWorkload)
+    }
+
+    async fn cancel(&self, run: &Run<'_>) {
+        let Some(state) = self.runs.lock().unwrap().remove(&run.name) else {
+            return;
+        };
+        self.cancel_jobs(&state.active).await;
+        if let Err(e) = fs::remove_dir_all(&state.dir).await {
+            warn!("Cannot remove directory {}: {e}", state.dir.display());
+        }
+    }
+}
+
+/// Quotes `value` as a single word for `sh`.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::{catalog::Catalog, executor::execute, test_support::{test_config, test_task, TempDir}};
+
+    /// Runs the batch script right away and records the job's state for the `sacct` stub. Jobs whose dependencies
+    /// did not complete are cancelled, and images named `sleep` keep running.
+    const SBATCH: &str = r#"#!/bin/sh
+bin=$(dirname "$0")
+id=$(( $(cat "$bin/next" 2>/dev/null || echo 100) + 1 ))
+echo $id > "$bin/next"
+echo "$@" >> "$bin/sbatch.log"
+state=COMPLETED
+for arg; do
+  case $arg in
+    --chdir=*) chdir=${arg#--chdir=} ;;
+    --output=*) output=${arg#--output=} ;;
+    --dependency=afterok:*) for dependency in $(echo ${arg#--dependency=afterok:} | tr : ' '); do
+        grep -q "^$dependency|COMPLETED|" "$bin/jobs" || state=CANCELLED
+      done ;;
+  esac
+  script=$arg
+done
+if [ $state = CANCELLED ]; then
+  echo "$id|CANCELLED by 0|0:0" >> "$bin/jobs"
+elif grep -q docker://sleep "$script"; then
+  echo "$id|RUNNING|0:0" >> "$bin/jobs"
+elif (cd "$chdir" && sh "$script") > "$output" 2>&1; then
+  echo "$id|COMPLETED|0:0" >> "$bin/jobs"
+else
+  echo "$id|FAILED|$?:0" >> "$bin/jobs"
+fi
+echo "$id;cluster"
+"#;
+
+    const SACCT: &str = "#!/bin/sh\ncat \"$(dirname \"$0\")/jobs\"\n";
+
+    const SCANCEL: &str = "#!/bin/sh\necho \"$@\" >> \"$(dirname \"$0\")/scancel.log\"\n";
+
+    /// Stands in for apptainer: concatenates the inputs into the output, prefixed with the image, or fails for the image `fail`.
+    const RUNTIME: &str = r#"#!/bin/sh
+data=$(echo "$3" | cut -d: -f1)
+image=${6#docker://}
+echo "running $image with GREETING=$GREETING"
+[ "$image" = fail ] && exit 3
+output=$data/${BK_OUTPUT#/data/}
+echo "$image" > "$output"
+for input in $(echo "$BK_INPUTS" | tr , ' '); do cat "$data/${input#/data/}" >> "$output"; done
+"#;
+
+    struct Cluster {
+        dir: TempDir,
+        executor: SlurmExecutor,
+    }
+
+    impl Cluster {
+        fn new() -> Self {
+            let dir = TempDir::new("slurm");
+            let bin = dir.join("bin");
+            std::fs::create_dir_all(&bin).unwrap();
+            std::fs::write(bin.join("jobs"), "").unwrap();
+            for (name, script) in [("sbatch", SBATCH), ("sacct", SACCT), ("scancel", SCANCEL), ("runtime", RUNTIME)] {
+                std::fs::write(bin.join(name), script).unwrap();
+                std::fs::set_permissions(bin.join(name), std::fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            let mut config = test_config("http://localhost/");
+            config.slurm = SlurmSettings {
+                work_dir: Some(dir.join("work")),
+                bin_dir: Some(bin.clone()),
+                partition: Some("analysis".into()),
+                poll_interval: Duration::from_millis(20),
+                container_runtime: bin.join("runtime").display().to_string(),
+            };
+            Cluster { executor: SlurmExecutor::new(&config).unwrap(), dir }
+        }
+
+        fn read(&self, file: &str) -> String {
+            std::fs::read_to_string(self.dir.join("bin").join(file)).unwrap_or_default()
+        }
+    }
+
+    fn task(steps: &str, timeout: Option<&str>) -> ExecutionTask {
+        let timeout = timeout.map(|timeout| format!(r#","timeout":"{timeout}""#)).unwrap_or_default();
+        let body = format!(r#"{{"schema_version":2,"executor":"HPCExecutor","workflow":{{"output":["b.txt"]{timeout},"steps":{steps}}}}}"#);
+        ExecutionTask::parse(test_task(&body, Duration::from_secs(10)), &Catalog::load(None, true).unwrap()).unwrap()
+    }
+
+    const STEPS: &str = r#"[
+        {"name":"a","image":"first","env":["GREETING=it's me"],"input":null,"output":"a.txt","timeout":"90s"},
+        {"name":"b","image":"IMAGE","env":null,"input":["a.txt"],"output":"b.txt"}
+    ]"#;
+
+    #[tokio::test]
+    async fn runs_steps_as_dependent_jobs() {
+        let cluster = Cluster::new();
+        let task = task(&STEPS.replace("IMAGE", "second"), None);
+        let body = execute(&cluster.executor, &task, 1).await.unwrap();
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(report["outputs"]["b.txt"]["content"], "second\nfirst\n");
+        assert_eq!(report["steps"][0]["logs"], "running first with GREETING=it's me\n");
+        assert_eq!(report["steps"][1]["status"], "succeeded");
+
+        let sbatch = cluster.read("sbatch.log");
+        assert!(sbatch.contains("--partition=analysis --time=1:30 "), "{sbatch}");
+        assert!(sbatch.contains("--dependency=afterok:101 --kill-on-invalid-dep=yes"), "{sbatch}");
+        assert!(!cluster.dir.join("work").join(format!("bk-orchestrator-{}-1", task.context.id)).exists());
+    }
+
+    #[tokio::test]
+    async fn reports_failed_and_cancelled_steps() {
+        let cluster = Cluster::new();
+        let task = task(&STEPS.replace("first", "fail").replace("IMAGE", "second"), None);
+        let Err(ExecutorError::StepFailed(steps)) = execute(&cluster.executor, &task, 1).await else {
+            panic!("the run did not fail");
+        };
+        let steps: serde_json::Value = serde_json::from_str(&steps).unwrap();
+        assert_eq!(steps[0]["status"], "failed");
+        assert_eq!(steps[0]["exit_code"], 3);
+        assert_eq!(steps[1]["status"], "cancelled");
+    }
+
+    #[tokio::test]
+    async fn cancels_jobs_exceeding_the_workflow_timeout() {
+        let cluster = Cluster::new();
+        let task = task(&STEPS.replace("IMAGE", "sleep"), Some("200ms"));
+        assert!(matches!(execute(&cluster.executor, &task, 1).await, Err(ExecutorError::TimedOut(_))));
+        assert_eq!(cluster.read("scancel.log"), "102\n");
+    }
+
+    #[test]
+    fn parses_job_states() {
+        assert_eq!(JobState::parse("PENDING", "0:0"), JobState::Pending);
+        assert_eq!(JobState::parse("COMPLETING", "0:0"), JobState::Running);
+        assert_eq!(JobState::parse("CANCELLED by 1000", "0:15"), JobState::Finished { status: StepStatus::Cancelled, exit_code: Some(0) });
+        assert_eq!(JobState::parse("OUT_OF_MEMORY", "0:125"), JobState::Finished { status: StepStatus::OutOfMemory, exit_code: Some(0) });
+        assert_eq!(JobState::parse("TIMEOUT", ""), JobState::Finished { status: StepStatus::TimedOut, exit_code: None });
+    }
+}
@@ -0,0 +1,192 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::Mutex, time::{Duration, SystemTime}};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{beam::{BeamResult, Status}, error::ExecutorError};
+
+/// Largest result body kept to answer re-deliveries with, so the state file stays small; for larger ones only the
+/// status is recorded.
+const MAX_KEPT_BODY: usize = 16 * 1024;
+
+/// What we know about a task id we have been handed before.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum TaskState {
+    /// Claimed and being executed by this process
+    Running,
+    /// Was running when the orchestrator stopped, so its outcome is unknown
+    Interrupted,
+    /// Answered with a final result, whose body is kept unless it is larger than `MAX_KEPT_BODY`
+    Finished {
+        status: Status,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    state: TaskState,
+    /// Beam stops delivering a task once its ttl has passed, so the entry can be dropped then
+    expires: SystemTime,
+}
+
+/// Remembers every task id handed to us to guarantee at-most-once execution, optionally persisted to a state file.
+#[derive(Debug)]
+pub struct TaskLedger {
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<Uuid, Entry>>,
+    /// Held while writing the state file, so that the writes land in the order of the changes
+    writing: tokio::sync::Mutex<()>,
+}
+
+impl TaskLedger {
+    /// Loads the ledger from `path` if it exists. Tasks that were running when it was written are marked as interrupted.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, ExecutorError> {
+        let mut entries: HashMap<Uuid, Entry> = match &path {
+            Some(path) if path.exists() => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot read state file {}: {e}", path.display())))?;
+                serde_json::from_str(&content)
+                    .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot parse state file {}: {e}", path.display())))?
+            },
+            Some(_) => HashMap::new(),
+            None => {
+                warn!("No state file configured, tasks re-delivered after a restart will be executed again");
+                HashMap::new()
+            }
+        };
+        prune(&mut entries);
+        for (id, entry) in entries.iter_mut() {
+            if matches!(entry.state, TaskState::Running) {
+                warn!("Task {} was running when the orchestrator stopped", id);
+                entry.state = TaskState::Interrupted;
+            }
+        }
+        // Nothing else runs yet, so the file is written right away
+        if let Some(path) = &path {
+            report_write(path, entries.len(), serde_json::to_vec(&entries).map_err(Into::into).and_then(|content| write_state(path, &content)));
+        }
+        Ok(TaskLedger { path, entries: Mutex::new(entries), writing: Default::default() })
+    }
+
+    /// Records the task as running unless it is already known, in which case its recorded state is returned.
+    pub async fn start(&self, id: Uuid, ttl: Duration) -> Option<TaskState> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries.get(&id) {
+                return Some(entry.state.clone());
+            }
+            entries.insert(id, Entry { state: TaskState::Running, expires: SystemTime::now() + ttl });
+        }
+        self.persist().await;
+        None
+    }
+
+    /// Records a final (`Succeeded` or `PermFailed`) result; other results are ignored.
+    pub async fn finish(&self, result: &BeamResult) {
+        if !matches!(result.status, Status::Succeeded | Status::PermFailed) {
+            return;
+        }
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let expires = entries.get(&result.task).map(|e| e.expires).unwrap_or_else(SystemTime::now);
+            let body = (result.body.len() <= MAX_KEPT_BODY).then(|| result.body.clone());
+            entries.insert(result.task, Entry { state: TaskState::Finished { status: result.status.clone(), body }, expires });
+        }
+        self.persist().await;
+    }
+
+    /// Drops a task that was recorded but never started, so that it is picked up again on the next delivery.
+    pub async fn forget(&self, id: Uuid) {
+        self.entries.lock().unwrap().remove(&id);
+        self.persist().await;
+    }
+
+    /// Drops expired entries and writes the others to the state file, off the async runtime's threads.
+    async fn persist(&self) {
+        let _writing = self.writing.lock().await;
+        // Taken after waiting for the previous write, so the latest changes are always written last
+        let (content, count) = {
+            let mut entries = self.entries.lock().unwrap();
+            prune(&mut entries);
+            (self.path.as_ref().map(|_| serde_json::to_vec(&*entries)), entries.len())
+        };
+        let (Some(path), Some(content)) = (self.path.clone(), content) else {
+            return;
+        };
+        let result = match content {
+            Ok(content) => tokio::task::spawn_blocking({
+                let path = path.clone();
+                move || write_state(&path, &content)
+            }).await.unwrap_or_else(|e| Err(e.into())),
+            Err(e) => Err(e.into()),
+        };
+        report_write(&path, count, result);
+    }
+}
+
+/// Drops the entries whose task's ttl has passed, as Beam no longer delivers them; running tasks have been stopped
+/// by then as well.
+fn prune(entries: &mut HashMap<Uuid, Entry>) {
+    let now = SystemTime::now();
+    entries.retain(|_, entry| entry.expires > now);
+}
+
+/// Replaces the state file, going through a temporary file so that it is never left half written.
+fn write_state(path: &Path, content: &[u8]) -> Result<(), std::io::Error> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
+
+fn report_write(path: &Path, count: usize, result: Result<(), std::io::Error>) {
+    match result {
+        Ok(()) => debug!("Persisted {} task states to {}", count, path.display()),
+        Err(e) => warn!("Cannot write state file {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app_id, TempDir, EXECUTOR_APP, REQUESTER_APP};
+
+    #[tokio::test]
+    async fn state_survives_restart() {
+        let dir = TempDir::new("ledger");
+        let path = dir.join("state.json");
+        let (running, finished, large) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        {
+            let ledger = TaskLedger::load(Some(path.clone())).unwrap();
+            assert!(ledger.start(running, Duration::from_secs(60)).await.is_none());
+            assert!(matches!(ledger.start(running, Duration::from_secs(60)).await, Some(TaskState::Running)));
+            assert!(ledger.start(finished, Duration::from_secs(60)).await.is_none());
+            ledger.finish(&BeamResult::succeeded(app_id(EXECUTOR_APP), vec![app_id(REQUESTER_APP)], finished, "done".into())).await;
+            ledger.start(large, Duration::from_secs(60)).await;
+            ledger.finish(&BeamResult::succeeded(app_id(EXECUTOR_APP), vec![app_id(REQUESTER_APP)], large, "x".repeat(MAX_KEPT_BODY + 1))).await;
+        }
+        assert!(fs::metadata(&path).unwrap().len() < MAX_KEPT_BODY as u64);
+        let ledger = TaskLedger::load(Some(path.clone())).unwrap();
+        assert!(matches!(ledger.start(running, Duration::from_secs(60)).await, Some(TaskState::Interrupted)));
+        assert!(matches!(ledger.start(finished, Duration::from_secs(60)).await, Some(TaskState::Finished { status: Status::Succeeded, body: Some(body) }) if body == "done"));
+        assert!(matches!(ledger.start(large, Duration::from_secs(60)).await, Some(TaskState::Finished { status: Status::Succeeded, body: None })));
+    }
+
+    #[tokio::test]
+    async fn expired_and_forgotten_tasks_are_dropped() {
+        let ledger = TaskLedger::load(None).unwrap();
+        let (expired, running, forgotten) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        ledger.start(expired, Duration::ZERO).await;
+        ledger.start(running, Duration::ZERO).await;
+        ledger.finish(&BeamResult::perm_failed(app_id(EXECUTOR_APP), vec![app_id(REQUESTER_APP)], expired, "failed".into())).await;
+        ledger.start(forgotten, Duration::from_secs(60)).await;
+        ledger.forget(forgotten).await;
+        assert!(ledger.start(expired, Duration::from_secs(60)).await.is_none());
+        assert!(ledger.start(running, Duration::from_secs(60)).await.is_none());
+        assert!(ledger.start(forgotten, Duration::from_secs(60)).await.is_none());
+    }
+}
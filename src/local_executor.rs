@@ -0,0 +1,378 @@
+use std::{collections::{BTreeMap, HashMap}, fs::File, path::{Path, PathBuf}, process::Stdio, sync::Mutex};
+
+use async_trait::async_trait;
+use tokio::{fs, process::Command, time::{timeout_at, Instant}};
+use tracing::{debug, info, warn};
+
+use crate::{config::BeamConfig, dag::WorkflowGraph, error::ExecutorError, executor::{Capabilities, Executor, Run}, validation::ValidationError, workflow::{ExecutionTask, OutputFile, RunReport, SiteLimits, StepReport, StepStatus, Workflow, WorkflowSteps}};
+
+/// Configuration of `LocalExecutor`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LocalSettings {
+    /// Directory every run gets its own working directory in
+    pub work_dir: Option<PathBuf>,
+    /// The programs steps may run, by the name they use in `WorkflowSteps.image`
+    pub commands: BTreeMap<String, PathBuf>,
+}
+
+/// Parses an entry of `--local-commands`, e.g. `count=/opt/analysis/count.sh`.
+pub(crate) fn parse_local_command(entry: &str) -> Result<(String, PathBuf), String> {
+    match entry.split_once('=') {
+        Some((name, path)) if !name.is_empty() && Path::new(path).is_absolute() => Ok((name.to_string(), PathBuf::from(path))),
+        _ => Err(format!("{entry:?} is not of the form name=/absolute/path")),
+    }
+}
+
+/// Variables steps may not set, as they change which programs and libraries are run. `BK_*` are set by the executor.
+const RESERVED_ENV: [&str; 5] = ["PATH", "IFS", "ENV", "BASH_ENV", "SHELLOPTS"];
+const RESERVED_ENV_PREFIXES: [&str; 3] = ["LD_", "DYLD_", "BK_"];
+
+fn is_allowed_env(key: &str) -> bool {
+    !RESERVED_ENV.contains(&key) && !RESERVED_ENV_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+/// Runs the steps as child processes of the orchestrator, one after the other, for development and for sites
+/// without Docker. A step's `image` names one of the allowed commands, which is started in the run's working
+/// directory with the step's environment, `BK_INPUTS` and `BK_OUTPUT`, and its input files as arguments.
+#[derive(Debug)]
+pub(crate) struct LocalExecutor {
+    work_dir: PathBuf,
+    commands: BTreeMap<String, PathBuf>,
+    limits: SiteLimits,
+    runs: Mutex<HashMap<String, LocalRun>>,
+}
+
+/// State of a run of the `LocalExecutor`.
+#[derive(Debug)]
+struct LocalRun {
+    task: ExecutionTask,
+    dir: PathBuf,
+    steps: Vec<StepReport>,
+}
+
+/// The process group of a running step, led by the step's process. Dropping it kills every process of the group,
+/// including those the step's command started in the background. It must be dropped before the leader is reaped:
+/// until then the leader's id, and with it the group's, cannot be reused by the kernel.
+struct ProcessGroup(u32);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        // SAFETY: kill(2) has no memory safety preconditions. Process ids are positive and below `pid_max` (at most
+        // 2^22), so the cast is lossless and the negation addresses the group led by this id rather than a single
+        // process or every process. That group is the step's own, as its leader has not been reaped yet.
+        unsafe { libc::kill(-(self.0 as libc::pid_t), libc::SIGKILL) };
+    }
+}
+
+/// Blocks until the process `pid` has exited, without reaping it.
+fn wait_for_exit(pid: u32) -> std::io::Result<()> {
+    loop {
+        // SAFETY: `info` is a valid siginfo_t for waitid(2) to write to. `WNOWAIT` leaves the process to be reaped
+        // by its `Child`.
+        let result = unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WEXITED | libc::WNOWAIT)
+        };
+        if result == 0 {
+            return Ok(());
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+impl LocalExecutor {
+    pub fn new(config: &BeamConfig) -> Result<Self, ExecutorError> {
+        let work_dir = config.local.work_dir.clone()
+            .ok_or_else(|| ExecutorError::ConfigurationError("LocalExecutor requires --local-work-dir".into()))?;
+        if config.local.commands.is_empty() {
+            return Err(ExecutorError::ConfigurationError("LocalExecutor requires at least one command in --local-commands".into()));
+        }
+        Ok(LocalExecutor { work_dir, commands: config.local.commands.clone(), limits: config.limits.clone(), runs: Mutex::default() })
+    }
+
+    /// Runs the steps in dependency order and stops at the first one that does not succeed.
+    async fn run_steps(&self, task: &ExecutionTask, dir: &Path, workflow_deadline: Option<Instant>, steps: &mut Vec<StepReport>) -> Result<(), ExecutorError> {
+        let workflow = &task.workflow;
+        for index in WorkflowGraph::build(workflow)?.order {
+            let step = &workflow.steps[index];
+            let step_deadline = self.limits.step_timeout(step).map(|timeout| Instant::now() + timeout);
+            let deadline = match (step_deadline, workflow_deadline) {
+                (Some(step), Some(workflow)) => Some(step.min(workflow)),
+                (step, workflow) => step.or(workflow),
+            };
+            let report = self.run_step(task, step, dir, deadline).await?;
+            let succeeded = matches!(report.status, StepStatus::Succeeded);
+            steps.push(report);
+            if !succeeded {
+                warn!("Step {} failed, not running the remaining steps", step.name);
+                return Err(ExecutorError::from_steps(steps));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the step's command in its own process group and waits for it to exit, then kills what is left of the
+    /// group. The group is killed and the step reported as timed out once `deadline` has passed, and killed with an
+    /// error if the task's ttl elapses first.
+    async fn run_step(&self, task: &ExecutionTask, step: &WorkflowSteps, dir: &Path, deadline: Option<Instant>) -> Result<StepReport, ExecutorError> {
+        let program = self.commands.get(&step.image)
+            .ok_or_else(|| ExecutorError::TaskRejected(format!("Command {} of step {} is not allowed", step.image, step.name)))?;
+        let data = dir.join("data");
+        let log_path = dir.join("logs").join(format!("{}.log", step.name));
+        let io_error = |e: std::io::Error| ExecutorError::StepFailed(format!("Cannot start step {}: {e}", step.name));
+        let log = File::create(&log_path).map_err(io_error)?;
+        let inputs: Vec<PathBuf> = step.inputs().iter().map(|input| data.join(input)).collect();
+        let inputs_env: Vec<String> = inputs.iter().map(|input| input.display().to_string()).collect();
+
+        let mut command = Command::new(program);
+        command.args(&inputs)
+            .current_dir(&data)
+            .env_clear()
+            .envs(std::env::var_os("PATH").map(|path| ("PATH", path)))
+            .envs(step.env.iter().flatten().filter_map(|entry| entry.split_once('=')).filter(|(key, _)| is_allowed_env(key)))
+            .env("BK_INPUTS", inputs_env.join(","))
+            .env("BK_OUTPUT", data.join(&step.output))
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().map_err(io_error)?)
+            .stderr(log)
+            .process_group(0)
+            .kill_on_drop(true);
+        info!("Starting step {} ({})", step.name, program.display());
+        let mut child = command.spawn().map_err(io_error)?;
+        let pid = child.id().ok_or_else(|| ExecutorError::StepFailed(format!("Step {} exited before it was started", step.name)))?;
+        // Declared after `child`, so that it is dropped before `child` if this future is dropped
+        let process_group = ProcessGroup(pid);
+
+        let timed_out = matches!(deadline, Some(deadline) if deadline < task.context.expires_at);
+        let exited = tokio::task::spawn_blocking(move || wait_for_exit(pid));
+        let expired = match timeout_at(deadline.filter(|_| timed_out).unwrap_or(task.context.expires_at), exited).await {
+            Ok(exited) => {
+                exited.unwrap_or_else(|e| Err(e.into())).map_err(|e| ExecutorError::StepFailed(format!("Cannot wait for step {}: {e}", step.name)))?;
+                false
+            },
+            Err(_) => {
+                warn!("{}, killing step {}", if timed_out { "Timeout exceeded" } else { "Task ttl elapsed" }, step.name);
+                true
+            },
+        };
+        drop(process_group);
+        let status = child.wait().await.map_err(|e| ExecutorError::StepFailed(format!("Cannot wait for step {}: {e}", step.name)))?;
+        if expired && !timed_out {
+            return Err(ExecutorError::TaskExpired(format!("Step {} was killed because the task's ttl elapsed", step.name)));
+        }
+        let logs = String::from_utf8_lossy(&fs::read(&log_path).await.unwrap_or_default()).into_owned();
+        if expired {
+            return Ok(StepReport { name: step.name.clone(), status: StepStatus::TimedOut, exit_code: None, logs });
+        }
+        info!("Step {} exited with {status}", step.name);
+        let exit_code = status.code().map(i64::from);
+        let status = if status.success() { StepStatus::Succeeded } else { StepStatus::Failed };
+        Ok(StepReport { name: step.name.clone(), status, exit_code, logs })
+    }
+}
+
+#[async_trait]
+impl Executor for LocalExecutor {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { resource_limits: false, step_timeouts: true, parallelism: false }
+    }
+
+    fn check_workflow(&self, workflow: &Workflow) -> Vec<ValidationError> {
+        let mut problems = Vec::new();
+        for step in &workflow.steps {
+            if !self.commands.contains_key(&step.image) {
+                problems.push(ValidationError::CommandNotAllowed { step: step.name.clone(), command: step.image.clone() });
+            }
+            let variables = step.env.iter().flatten().filter_map(|entry| entry.split_once('=')).map(|(key, _)| key);
+            problems.extend(variables.filter(|key| !is_allowed_env(key))
+                .map(|key| ValidationError::EnvNotAllowed { step: step.name.clone(), variable: key.to_string() }));
+        }
+        problems
+    }
+
+    async fn prepare(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        WorkflowGraph::build(&run.task.workflow)?;
+        let dir = self.work_dir.join(&run.name);
+        let state = LocalRun { task: run.task.clone(), dir: dir.clone(), steps: Vec::new() };
+        self.runs.lock().unwrap().insert(run.name.clone(), state);
+        for subdir in ["data", "logs"] {
+            fs::create_dir_all(dir.join(subdir)).await
+                .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot create directory {}: {e}", dir.display())))?;
+        }
+        debug!("Prepared directory {}", dir.display());
+        Ok(())
+    }
+
+    async fn run(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        let (task, dir) = self.runs.lock().unwrap().get(&run.name).map(|state| (state.task.clone(), state.dir.clone()))
+            .ok_or_else(|| ExecutorError::StepFailed(format!("Run {} was not prepared", run.name)))?;
+        let workflow_deadline = self.limits.workflow_timeout(&task.workflow).map(|timeout| Instant::now() + timeout);
+        let mut steps = Vec::new();
+        let result = self.run_steps(&task, &dir, workflow_deadline, &mut steps).await;
+        if let Some(state) = self.runs.lock().unwrap().get_mut(&run.name) {
+            state.steps = steps;
+        }
+        result
+    }
+
+    async fn collect_outputs(&self, run: &Run<'_>) -> Result<String, ExecutorError> {
+        let (task, dir, steps) = self.runs.lock().unwrap().get(&run.name)
+            .map(|state| (state.task.clone(), state.dir.clone(), state.steps.clone()))
+            .ok_or_else(|| ExecutorError::StepFailed(format!("Run {} was not prepared", run.name)))?;
+        let mut outputs = BTreeMap::new();
+        for file in &task.workflow.output {
+            let content = fs::read(dir.join("data").join(file)).await
+                .map_err(|e| ExecutorError::StepFailed(format!("Output {file} was not created: {e}")))?;
+            outputs.insert(file.clone(), OutputFile::from(content));
+        }
+        serde_json::to_string(&RunReport { steps, outputs }).map_err(ExecutorError::UnableToParseWorkload)
+    }
+
+    async fn cancel(&self, run: &Run<'_>) {
+        let Some(state) = self.runs.lock().unwrap().remove(&run.name) else {
+            return;
+        };
+        if let Err(e) = fs::remove_dir_all(&state.dir).await {
+            warn!("Cannot remove directory {}: {e}", state.dir.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, time::Duration};
+
+    use super::*;
+    use crate::{catalog::Catalog, executor::execute, policy::ImagePolicy, test_support::{test_config, test_task, TempDir}, validation::validate_workflow};
+
+    /// Sleeps in a background process, whose id it writes to `$PID_FILE`.
+    const SLEEP: &str = "#!/bin/sh\nsleep 5 &\necho $! > \"$PID_FILE\"\nwait\n";
+
+    /// Leaves a background process, whose id it writes to `$PID_FILE`, running when it exits.
+    const DAEMON: &str = "#!/bin/sh\nsleep 5 &\necho $! > \"$PID_FILE\"\necho started > \"$BK_OUTPUT\"\n";
+
+    /// Writes its environment to its output and its inputs to stdout and stderr.
+    const GREET: &str = "#!/bin/sh\necho \"$GREETING from $PWD\" > \"$BK_OUTPUT\"\necho stdout\necho stderr >&2\n";
+
+    /// Concatenates its arguments into its output.
+    const CONCAT: &str = "#!/bin/sh\ncat \"$@\" > \"$BK_OUTPUT\"\n[ \"$BK_INPUTS\" = \"$1\" ]\n";
+
+    struct Site {
+        dir: TempDir,
+        executor: LocalExecutor,
+    }
+
+    impl Site {
+        fn new() -> Self {
+            let dir = TempDir::new("local");
+            std::fs::create_dir(dir.join("bin")).unwrap();
+            let mut commands = BTreeMap::new();
+            for (name, script) in [("greet", GREET), ("concat", CONCAT), ("sleep", SLEEP), ("daemon", DAEMON)] {
+                let path = dir.join("bin").join(name);
+                std::fs::write(&path, script).unwrap();
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+                commands.insert(name.to_string(), path);
+            }
+            let mut config = test_config("http://localhost/");
+            config.local = LocalSettings { work_dir: Some(dir.join("work")), commands };
+            // Only applies to container images, not to the commands' names
+            config.limits.images = ImagePolicy::new(vec!["registry.example.de/*".into()], true, false);
+            Site { executor: LocalExecutor::new(&config).unwrap(), dir }
+        }
+    }
+
+    fn task(first: &str, second: &str) -> ExecutionTask {
+        let body = format!(r#"{{"schema_version":2,"executor":"LocalExecutor","workflow":{{"output":["b.txt"],"steps":[
+            {{"name":"a","image":"{first}","env":["GREETING=hello"],"input":null,"output":"a.txt","timeout":"200ms"}},
+            {{"name":"b","image":"{second}","env":null,"input":["a.txt"],"output":"b.txt"}}
+        ]}}}}"#);
+        ExecutionTask::parse(test_task(&body, Duration::from_secs(10)), &Catalog::load(None, true).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn runs_steps_as_processes() {
+        let site = Site::new();
+        let task = task("greet", "concat");
+        assert_eq!(validate_workflow(&task.workflow, &site.executor.limits), Ok(()));
+        assert!(site.executor.check_workflow(&task.workflow).is_empty());
+        let body = execute(&site.executor, &task, 1).await.unwrap();
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let data = site.dir.join("work").join(format!("bk-orchestrator-{}-1", task.context.id)).join("data");
+        assert_eq!(report["outputs"]["b.txt"]["content"], format!("hello from {}\n", data.display()));
+        assert_eq!(report["steps"][0]["logs"], "stdout\nstderr\n");
+        assert_eq!(report["steps"][1]["exit_code"], 0);
+        assert!(!data.exists());
+    }
+
+    /// Whether the process exists and is not a zombie waiting to be reaped.
+    fn is_running(pid: &str) -> bool {
+        match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+            // The state follows the parenthesized command name
+            Ok(stat) => !stat.rsplit(')').next().unwrap_or_default().trim_start().starts_with('Z'),
+            Err(_) => false,
+        }
+    }
+
+    /// Waits for the process whose id is in `pid_file` to be killed.
+    async fn assert_killed(pid_file: &Path) {
+        let pid = std::fs::read_to_string(pid_file).unwrap();
+        let pid = pid.trim();
+        for _ in 0..50 {
+            if !is_running(pid) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("the step's background process {pid} is still running");
+    }
+
+    #[tokio::test]
+    async fn kills_what_steps_leave_running() {
+        let site = Site::new();
+        let mut task = task("daemon", "concat");
+        let pid_file = site.dir.join("daemon.pid");
+        task.workflow.steps[0].env = Some(vec![format!("PID_FILE={}", pid_file.display())]);
+        let body = execute(&site.executor, &task, 1).await.unwrap();
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(report["outputs"]["b.txt"]["content"], "started\n");
+        assert_killed(&pid_file).await;
+    }
+
+    #[tokio::test]
+    async fn kills_steps_exceeding_their_timeout() {
+        let site = Site::new();
+        let mut task = task("sleep", "concat");
+        let pid_file = site.dir.join("sleep.pid");
+        task.workflow.steps[0].env = Some(vec![format!("PID_FILE={}", pid_file.display())]);
+        let Err(ExecutorError::TimedOut(steps)) = execute(&site.executor, &task, 1).await else {
+            panic!("the run did not time out");
+        };
+        let steps: serde_json::Value = serde_json::from_str(&steps).unwrap();
+        assert_eq!(steps.as_array().unwrap().len(), 1);
+        assert_eq!(steps[0]["status"], "timed_out");
+
+        assert_killed(&pid_file).await;
+    }
+
+    #[test]
+    fn only_allows_configured_commands() {
+        let site = Site::new();
+        assert!(site.executor.check_workflow(&task("greet", "concat").workflow).is_empty());
+        assert_eq!(site.executor.check_workflow(&task("greet", "rm").workflow), vec![ValidationError::CommandNotAllowed { step: "b".into(), command: "rm".into() }]);
+        assert_eq!(parse_local_command("count=/opt/count.sh"), Ok(("count".into(), PathBuf::from("/opt/count.sh"))));
+        assert!(parse_local_command("count=count.sh").is_err());
+    }
+
+    #[test]
+    fn rejects_environment_changing_the_programs_run() {
+        let site = Site::new();
+        let mut task = task("greet", "concat");
+        task.workflow.steps[1].env = Some(vec!["PATH=/tmp".into(), "LD_PRELOAD=/tmp/hook.so".into(), "BK_OUTPUT=/etc/passwd".into(), "THREADS=4".into()]);
+        let not_allowed = |variable: &str| ValidationError::EnvNotAllowed { step: "b".into(), variable: variable.into() };
+        assert_eq!(site.executor.check_workflow(&task.workflow), vec![not_allowed("PATH"), not_allowed("LD_PRELOAD"), not_allowed("BK_OUTPUT")]);
+        assert!(is_allowed_env("GREETING"));
+    }
+}
@@ -1,22 +1,39 @@
 mod beam;
+mod catalog;
 mod error;
+mod format;
 mod docker_executor;
+mod executor;
+mod hpc_executor;
+mod local_executor;
 mod workflow;
 mod config;
 mod banner;
+mod dag;
 mod logger;
+mod ledger;
+mod policy;
+mod pull;
+mod schema;
+mod template;
+mod validation;
+#[cfg(test)]
+mod test_support;
 
-use std::{time::Duration, process::exit};
+use std::{time::Duration, process::exit, sync::Arc};
 
+use beam::{BeamApi, BeamClient, BeamResult, BeamTask};
+use catalog::Catalog;
 use config::BeamConfig;
-use error::ExecutorError;
-use tokio::{sync::mpsc::{Receiver, Sender, self}, time::sleep};
+use ledger::{TaskLedger, TaskState};
+use futures_util::StreamExt;
+use error::{AttemptReport, ExecutorError, FailureReport};
+use tokio::{signal::unix::{signal, SignalKind}, sync::mpsc::{Receiver, Sender, self}, time::{sleep, Instant}};
 
 use reqwest::header::AUTHORIZATION;
-use bollard::Docker;
-
-use crate::workflow::{ExecutionTask, Executor};
-use tracing::{debug, error, warn, info};
+use crate::workflow::{ExecutionTask, TaskContext};
+use crate::validation::{validate_workflow, ValidationError};
+use tracing::{debug, error, warn, info, info_span, Instrument};
 
 #[tokio::main]
 async fn main() -> Result<(), ExecutorError> {
@@ -28,70 +45,579 @@ async fn main() -> Result<(), ExecutorError> {
     banner::print_banner();
 
     let config = config::BeamConfig::load()?;
+    let beam = Arc::new(BeamClient::new(&config)?);
+    let ledger = Arc::new(TaskLedger::load(config.state_file.clone())?);
+    tokio::spawn(reload_catalog_on_hangup(config.catalog.clone()));
 
-    let (tx, rx): (Sender<ExecutionTask>, Receiver<ExecutionTask>) = mpsc::channel(1024); 
+    let (tx, rx) = mpsc::channel::<ExecutionTask>(1024);
     let beam_tx = tx.clone();
-    let _beam_fetcher = tokio::spawn( async move { fetch_beam_tasks(beam_tx, config).await});
-    let executor = tokio::spawn(async move { handle_tasks(rx).await});
+    let executor_beam = beam.clone();
+    let executor_ledger = ledger.clone();
+    let executor_config = config.clone();
+    let _beam_fetcher = tokio::spawn( async move { fetch_beam_tasks(beam_tx, beam, config, ledger).await});
+    let executor = tokio::spawn(async move { handle_tasks(rx, executor_beam, executor_ledger, executor_config).await});
     _ = executor.await;
     error!("This should not be reached");
     Ok(())
 }
 
-async fn fetch_beam_tasks(tx: Sender<ExecutionTask>, config: BeamConfig) {
+/// Reloads the workflow catalog whenever the process receives SIGHUP.
+async fn reload_catalog_on_hangup(catalog: Arc<Catalog>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, the workflow catalog will not be reloaded: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = catalog.reload() {
+            error!("Keeping the previous workflow catalog: {e}");
+        }
+    }
+}
+
+async fn fetch_beam_tasks<B: BeamApi>(tx: Sender<ExecutionTask>, beam: Arc<B>, config: BeamConfig, ledger: Arc<TaskLedger>) {
     debug!("Beam-Connector started");
+    if config.task_stream {
+        let e = stream_beam_tasks(&tx, beam.as_ref(), &config, &ledger).await;
+        warn!("Falling back to polling for tasks: {}", e);
+    }
     loop {
-        beam::check_availability(&config).await;
-        let Ok(tasks) = beam::retrieve_tasks(&config).await else {
-            warn!("Cannot retreive Tasks");
+        beam.check_availability().await;
+        if let Err(e) = poll_beam_tasks(&tx, beam.as_ref(), &config, &ledger).await {
+            warn!("Cannot retreive Tasks: {}", e);
             sleep(Duration::from_secs(10)).await;
-            continue;
-        };
-        for task in tasks {
-            let answer = beam::answer_task_success(&task, &config).await;
-            if answer.is_err() {
-                warn!("Error answering task {:?}", task);
-                continue;
-            }
-            let task = ExecutionTask::try_from(task.clone());
-            if task.is_err() {
-                warn!("Error in task {:?}", task);
-                continue;
-            };
-            _ = tx.send(task.unwrap()).await.or_else(|e|{error!("Error: Could not send task to execution handler: {e}");Err(ExecutorError::ParsingError("()".into()))});
         }
     }
 }
 
-async fn handle_tasks(mut rx: Receiver<ExecutionTask>) {
+/// Keeps a task stream open, reconnecting with exponential backoff. Only returns if the proxy does not support streaming.
+async fn stream_beam_tasks<B: BeamApi>(tx: &Sender<ExecutionTask>, beam: &B, config: &BeamConfig, ledger: &TaskLedger) -> ExecutorError {
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        beam.check_availability().await;
+        match beam.subscribe_tasks().await {
+            Ok(mut tasks) => {
+                info!("Subscribed to task stream");
+                backoff = Duration::from_secs(1);
+                while let Some(task) = tasks.next().await {
+                    match task {
+                        Ok(task) => accept_task(tx, beam, config, ledger, task).await,
+                        Err(e) => warn!("Error in task stream: {}", e),
+                    }
+                }
+                warn!("Task stream closed, reconnecting in {:?}", backoff);
+            },
+            Err(e @ ExecutorError::StreamingUnsupported(_)) => return e,
+            Err(e) => warn!("Cannot subscribe to task stream, retrying in {:?}: {}", backoff, e),
+        }
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Fetches one batch of tasks, claims them and forwards them to the execution handler.
+async fn poll_beam_tasks<B: BeamApi>(tx: &Sender<ExecutionTask>, beam: &B, config: &BeamConfig, ledger: &TaskLedger) -> Result<(), ExecutorError> {
+    let tasks = beam.fetch_tasks().await?;
+    for task in tasks {
+        accept_task(tx, beam, config, ledger, task).await;
+    }
+    Ok(())
+}
+
+async fn accept_task<B: BeamApi>(tx: &Sender<ExecutionTask>, beam: &B, config: &BeamConfig, ledger: &TaskLedger, task: BeamTask) {
+    let context = TaskContext::from(&task);
+    if !task.from.same_broker(beam.app_id()) {
+        warn!(target: "audit", "Refusing task {} from {}: sender belongs to a different broker than {}", task.id, task.from, beam.app_id());
+        report_result(&context, Err(ExecutorError::TaskRejected(format!("{} is not registered with broker {}", task.from, beam.app_id().get_broker_id()))), beam, ledger).await;
+        return;
+    }
+    if !config.allowed_senders.permits(&task.from) {
+        warn!(target: "audit", "Refusing task {} from {}: sender is not in the allowlist", task.id, task.from);
+        report_result(&context, Err(ExecutorError::TaskRejected(format!("{} is not allowed to send tasks to {}", task.from, beam.app_id()))), beam, ledger).await;
+        return;
+    }
+    if task.is_expired() {
+        warn!("Skipping task {} from {}: ttl already elapsed", task.id, task.from);
+        report_result(&context, Err(ExecutorError::TaskExpired(format!("Task {} expired before it could be started", task.id))), beam, ledger).await;
+        return;
+    }
+    match ledger.start(task.id, task.ttl).await {
+        None => (),
+        Some(TaskState::Running) => {
+            debug!("Ignoring re-delivered task {}, it is still running", task.id);
+            return;
+        },
+        Some(TaskState::Interrupted) => {
+            warn!("Task {} was re-delivered after being interrupted by a restart, not running it again", task.id);
+            report_result(&context, Err(ExecutorError::TaskInterrupted(format!("Execution of task {} was interrupted by a restart of the orchestrator", task.id))), beam, ledger).await;
+            return;
+        },
+        Some(TaskState::Finished { status, body: Some(body) }) => {
+            info!("Task {} was re-delivered, answering with its recorded {:?} result", task.id, status);
+            let result = BeamResult { status, body, ..BeamResult::claimed(beam.app_id().clone(), vec![task.from.clone()], task.id) };
+            send_answer(result, beam, ledger).await;
+            return;
+        },
+        Some(TaskState::Finished { status, body: None }) => {
+            warn!("Task {} was re-delivered, but its {:?} result was too large to be recorded and is not sent again", task.id, status);
+            return;
+        }
+    }
+    if let Err(e) = beam.claim_task(&task).await {
+        warn!("Error claiming task {:?}: {}", task, e);
+        ledger.forget(task.id).await;
+        return;
+    }
+    let execution_task = match ExecutionTask::parse(task, &config.catalog) {
+        Ok(execution_task) => execution_task,
+        Err(e) => {
+            warn!("Error in task {} from {}: {}", context.id, context.from, e);
+            report_result(&context, Err(e), beam, ledger).await;
+            return;
+        }
+    };
+    let Some(executor) = config.executors.get(&execution_task.executor.name) else {
+        warn!("Refusing task {} from {}: executor {} is not configured", context.id, context.from, execution_task.executor.name);
+        let available = config.executors.names().join(", ");
+        report_result(&context, Err(ExecutorError::TaskRejected(format!("Executor {} is not available at this site, available executors: {available}", execution_task.executor.name))), beam, ledger).await;
+        return;
+    };
+    let mut problems = validate_workflow(&execution_task.workflow, &config.limits).err().unwrap_or_default();
+    problems.extend(executor.check_workflow(&execution_task.workflow));
+    for feature in executor.capabilities().unsupported(&execution_task.workflow) {
+        problems.push(ValidationError::UnsupportedFeature { executor: execution_task.executor.name.clone(), feature: feature.into() });
+    }
+    if !problems.is_empty() {
+        warn!("Workflow of task {} from {} has {} problem(s), not running it", context.id, context.from, problems.len());
+        report_result(&context, Err(ExecutorError::ValidationFailed(problems)), beam, ledger).await;
+        return;
+    }
+    if let Err(e) = tx.send(execution_task).await {
+        error!("Error: Could not send task to execution handler: {e}");
+    }
+}
+
+async fn handle_tasks<B: BeamApi>(mut rx: Receiver<ExecutionTask>, beam: Arc<B>, ledger: Arc<TaskLedger>, config: BeamConfig) {
     debug!("Executor Handler started");
     loop {
     let task = rx.recv().await;
     if let Some(task) = task {
-        info!("Got task {:?} in executor", task);
-        tokio::spawn(async move {run_orchestrator(task).await});
+        info!("Got task {} from {} (ttl {:?}) in executor: {:?}", task.context.id, task.context.from, task.context.ttl, task.executor);
+        debug!("Metadata of task {}: {}", task.context.id, task.context.metadata);
+        let beam = beam.clone();
+        let ledger = ledger.clone();
+        let config = config.clone();
+        let span = info_span!("task", id = %task.context.id, from = %task.context.from);
+        tokio::spawn(async move { execute_with_retries(task, beam.as_ref(), &ledger, &config).await }.instrument(span));
     } else {
         sleep(Duration::from_millis(50)).await;
     };
 
     }
 }
-async fn run_orchestrator(task: ExecutionTask) {
-    match task.executor.name {
-        Executor::DockerExecutor => {
-            debug!("Initializing Docker engine");
-            let docker =  Docker::connect_with_local_defaults().expect("Cannot initialize docker");
-            let version = docker.version().await.expect("Cannot connect to docker");
-            println!("Version: {:?}", version);
-            debug!("Starting Docker Job");
-            if let Err(err) = docker_executor::execute_docker_orchestrator(docker, task.workflow, uuid::Uuid::new_v4()).await {
-                warn!("Error executing task: {}", err);
-            }
+
+/// Runs the task according to the requester's `FailureStrategy`, publishing every failed attempt but the last as `TempFailed`.
+async fn execute_with_retries<B: BeamApi>(task: ExecutionTask, beam: &B, ledger: &TaskLedger, config: &BeamConfig) {
+    let context = &task.context;
+    let (max_tries, backoff) = context.failure_strategy.attempts();
+    let mut attempts = Vec::new();
+    for attempt in 1..=max_tries {
+        debug!("Executing task {} (attempt {}/{})", context.id, attempt, max_tries);
+        let error = match run_orchestrator(&task, attempt, config).await {
+            Ok(output) => {
+                report_result(context, Ok(output), beam, ledger).await;
+                return;
+            },
+            Err(e) => e,
+        };
+        let retryable = error.is_retryable() && Instant::now() + backoff < context.expires_at;
+        let report = AttemptReport { attempt, error: error.report() };
+        attempts.push(report.clone());
+        if !retryable || attempt == max_tries {
+            let failure = FailureReport { error: report.error, attempts };
+            warn!("Task {} failed permanently after {} attempt(s): {}", context.id, attempt, error);
+            let body = serde_json::to_string(&failure).unwrap_or_else(|_| error.to_result_body());
+            send_answer(BeamResult::perm_failed(beam.app_id().clone(), vec![context.from.clone()], context.id, body), beam, ledger).await;
+            return;
+        }
+        warn!("Attempt {}/{} of task {} failed, retrying in {:?}: {}", attempt, max_tries, context.id, backoff, error);
+        let body = serde_json::to_string(&report).unwrap_or_else(|_| error.to_result_body());
+        send_answer(BeamResult::temp_failed(beam.app_id().clone(), vec![context.from.clone()], context.id, body), beam, ledger).await;
+        sleep(backoff).await;
+    }
+}
+
+async fn run_orchestrator(task: &ExecutionTask, attempt: usize, config: &BeamConfig) -> Result<String, ExecutorError> {
+    let Some(executor) = config.executors.get(&task.executor.name) else {
+        warn!("Executor {} is not configured", task.executor.name);
+        return Err(ExecutorError::NotImplemented(task.executor.name.clone()));
+    };
+    debug!("Starting {} job", task.executor.name);
+    executor::execute(executor.as_ref(), task, attempt).await
+}
+
+/// Sends the final outcome of a task back to its requester.
+async fn report_result<B: BeamApi>(context: &TaskContext, result: Result<String, ExecutorError>, beam: &B, ledger: &TaskLedger) {
+    let answer = match result {
+        Ok(output) => {
+            info!("Task {} succeeded", context.id);
+            BeamResult::succeeded(beam.app_id().clone(), vec![context.from.clone()], context.id, output)
         },
-        _ => {
-            warn!("Executor {:?} not implemented", task.executor.name)
+        Err(e) => {
+            warn!("Error executing task {}: {}", context.id, e);
+            BeamResult::perm_failed(beam.app_id().clone(), vec![context.from.clone()], context.id, e.to_result_body())
         }
     };
+    send_answer(answer, beam, ledger).await;
 }
 
+/// Sends a result, recording it in the ledger if it is final.
+async fn send_answer<B: BeamApi>(result: BeamResult, beam: &B, ledger: &TaskLedger) {
+    ledger.finish(&result).await;
+    if let Err(e) = beam.answer_task(&result).await {
+        warn!("Error answering task {}: {}", result.task, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use async_trait::async_trait;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::beam::{AppId, FailureStrategy, Retry, Status};
+    use crate::executor::ExecutorRegistry;
+    use crate::policy::SenderAllowlist;
+    use crate::test_support::{app_id, test_config, test_task as task, FailingExecutor, MockBeamProxy, EXECUTOR_APP, WORKFLOW};
+    use crate::workflow::{StepReport, StepStatus};
+
+    /// In-memory stand-in for the Beam proxy.
+    struct FakeBeam {
+        app_id: AppId,
+        tasks: Mutex<VecDeque<Vec<BeamTask>>>,
+        results: Mutex<Vec<BeamResult>>,
+    }
+
+    impl FakeBeam {
+        fn new(batches: Vec<Vec<BeamTask>>) -> Self {
+            FakeBeam {
+                app_id: app_id(EXECUTOR_APP),
+                tasks: Mutex::new(batches.into()),
+                results: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// Delivers `task` again with the next fetch, like a proxy redelivering it.
+        fn deliver(&self, task: &BeamTask) {
+            self.tasks.lock().unwrap().push_back(vec![task.clone()]);
+        }
+
+        fn statuses(&self) -> Vec<(Uuid, Status)> {
+            self.results.lock().unwrap().iter().map(|r| (r.task, r.status.clone())).collect()
+        }
+    }
+
+    #[async_trait]
+    impl BeamApi for FakeBeam {
+        fn app_id(&self) -> &AppId {
+            &self.app_id
+        }
+
+        async fn check_availability(&self) {}
+
+        async fn fetch_tasks(&self) -> Result<Vec<BeamTask>, ExecutorError> {
+            Ok(self.tasks.lock().unwrap().pop_front().unwrap_or_default())
+        }
+
+        async fn answer_task(&self, result: &BeamResult) -> Result<(), ExecutorError> {
+            self.results.lock().unwrap().push(result.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_task_is_claimed_and_forwarded() {
+        let task = task(WORKFLOW, Duration::from_secs(10));
+        let beam = FakeBeam::new(vec![vec![task.clone()]]);
+        let (tx, mut rx) = mpsc::channel(1);
+        poll_beam_tasks(&tx, &beam, &test_config("http://localhost/"), &TaskLedger::load(None).unwrap()).await.unwrap();
+
+        assert_eq!(beam.statuses(), vec![(task.id, Status::Claimed)]);
+        let forwarded = rx.try_recv().unwrap();
+        assert_eq!(forwarded.context.id, task.id);
+        assert_eq!(forwarded.context.from, task.from);
+    }
+
+    #[tokio::test]
+    async fn expired_task_is_rejected_without_claiming() {
+        let task = task(WORKFLOW, Duration::ZERO);
+        let beam = FakeBeam::new(vec![vec![task.clone()]]);
+        let (tx, mut rx) = mpsc::channel(1);
+        poll_beam_tasks(&tx, &beam, &test_config("http://localhost/"), &TaskLedger::load(None).unwrap()).await.unwrap();
+
+        assert_eq!(beam.statuses(), vec![(task.id, Status::PermFailed)]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn task_from_unlisted_sender_is_refused() {
+        let task = task(WORKFLOW, Duration::from_secs(10));
+        let beam = FakeBeam::new(vec![vec![task.clone()]]);
+        let config = BeamConfig {
+            allowed_senders: SenderAllowlist::new(vec!["*.proxy3.broker.example.de".into()]),
+            ..test_config("http://localhost/")
+        };
+        let (tx, mut rx) = mpsc::channel(1);
+        poll_beam_tasks(&tx, &beam, &config, &TaskLedger::load(None).unwrap()).await.unwrap();
+
+        assert_eq!(beam.statuses(), vec![(task.id, Status::PermFailed)]);
+        assert!(beam.results.lock().unwrap()[0].body.contains("TaskRejected"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn task_from_other_broker_is_refused() {
+        let mut task = task(WORKFLOW, Duration::from_secs(10));
+        task.from = app_id("requester.proxy2.broker.example.com");
+        let beam = FakeBeam::new(vec![vec![task.clone()]]);
+        let (tx, mut rx) = mpsc::channel(1);
+        poll_beam_tasks(&tx, &beam, &test_config("http://localhost/"), &TaskLedger::load(None).unwrap()).await.unwrap();
+
+        assert_eq!(beam.statuses(), vec![(task.id, Status::PermFailed)]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unparsable_task_is_claimed_then_failed() {
+        let task = task("not a workflow", Duration::from_secs(10));
+        let beam = FakeBeam::new(vec![vec![task.clone()]]);
+        let (tx, mut rx) = mpsc::channel(1);
+        poll_beam_tasks(&tx, &beam, &test_config("http://localhost/"), &TaskLedger::load(None).unwrap()).await.unwrap();
+
+        assert_eq!(beam.statuses(), vec![(task.id, Status::Claimed), (task.id, Status::PermFailed)]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_workflow_is_failed_with_all_problems() {
+        let body = r#"{"executor":{"name":"DockerExecutor"},"workflow":{"output":["missing.csv"],"steps":[
+            {"name":"s","image":"i","env":null,"input":["nowhere.csv"],"output":"out.csv"},
+            {"name":"s","image":"i","env":null,"input":null,"output":"out.csv"}]}}"#;
+        let task = task(body, Duration::from_secs(10));
+        let beam = FakeBeam::new(vec![vec![task.clone()]]);
+        let (tx, mut rx) = mpsc::channel(1);
+        poll_beam_tasks(&tx, &beam, &test_config("http://localhost/"), &TaskLedger::load(None).unwrap()).await.unwrap();
+
+        assert_eq!(beam.statuses(), vec![(task.id, Status::Claimed), (task.id, Status::PermFailed)]);
+        assert!(rx.try_recv().is_err());
+        let report: serde_json::Value = serde_json::from_str(&beam.results.lock().unwrap()[1].body).unwrap();
+        assert_eq!(report["error"], "ValidationFailed");
+        let kinds: Vec<_> = report["problems"].as_array().unwrap().iter().map(|p| p["kind"].as_str().unwrap()).collect();
+        assert_eq!(kinds, vec!["duplicate_step_name", "duplicate_output", "missing_input", "missing_workflow_output"]);
+    }
+
+    #[tokio::test]
+    async fn redelivered_task_runs_at_most_once() {
+        let task = task(WORKFLOW, Duration::from_secs(10));
+        let beam = FakeBeam::new(vec![vec![task.clone()], vec![task.clone()]]);
+        let ledger = TaskLedger::load(None).unwrap();
+        let config = test_config("http://localhost/");
+        let (tx, mut rx) = mpsc::channel(2);
+        poll_beam_tasks(&tx, &beam, &config, &ledger).await.unwrap();
+        poll_beam_tasks(&tx, &beam, &config, &ledger).await.unwrap();
+
+        assert_eq!(rx.try_recv().unwrap().context.id, task.id);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(beam.statuses(), vec![(task.id, Status::Claimed)]);
+
+        let done = BeamResult::succeeded(beam.app_id().clone(), vec![task.from.clone()], task.id, "done".into());
+        send_answer(done, &beam, &ledger).await;
+        beam.deliver(&task);
+        poll_beam_tasks(&tx, &beam, &config, &ledger).await.unwrap();
+
+        assert!(rx.try_recv().is_err());
+        assert_eq!(beam.statuses(), vec![(task.id, Status::Claimed), (task.id, Status::Succeeded), (task.id, Status::Succeeded)]);
+    }
 
+    #[tokio::test]
+    async fn beam_client_talks_to_proxy() {
+        let proxy = MockBeamProxy::start().await;
+        let client = BeamClient::new(&proxy.config()).unwrap();
+        client.check_availability().await;
+        let task = task(WORKFLOW, Duration::from_secs(10));
+        proxy.enqueue(task.clone());
+
+        let fetched = client.fetch_tasks().await.unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].id, task.id);
+        assert_eq!(fetched[0].ttl, task.ttl);
+
+        client.claim_task(&fetched[0]).await.unwrap();
+        assert!(client.fetch_tasks().await.unwrap().is_empty());
+        let results = proxy.results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, Status::Claimed);
+        assert_eq!(results[0].to, vec![task.from]);
+    }
+
+    #[tokio::test]
+    async fn malformed_task_does_not_block_the_others() {
+        let proxy = MockBeamProxy::start().await;
+        let client = BeamClient::new(&proxy.config()).unwrap();
+        let malformed = || {
+            let mut task = serde_json::to_value(task(WORKFLOW, Duration::from_secs(10))).unwrap();
+            task["ttl"] = "soon".into();
+            task
+        };
+        let (polled, streamed) = (malformed(), malformed());
+        let valid = task(WORKFLOW, Duration::from_secs(10));
+        proxy.enqueue_json(polled.clone());
+        proxy.enqueue(valid.clone());
+
+        let fetched = client.fetch_tasks().await.unwrap();
+        assert_eq!(fetched.iter().map(|task| task.id).collect::<Vec<_>>(), vec![valid.id]);
+        client.claim_task(&fetched[0]).await.unwrap();
+
+        proxy.enable_streaming();
+        proxy.enqueue_json(streamed.clone());
+        let next = task(WORKFLOW, Duration::from_secs(10));
+        proxy.enqueue(next.clone());
+        let mut tasks = client.subscribe_tasks().await.unwrap();
+        let streamed_task = tokio::time::timeout(Duration::from_secs(5), tasks.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(streamed_task.id, next.id);
+
+        let results = proxy.results();
+        for malformed in [polled, streamed] {
+            let rejection = results.iter().find(|result| result.task.to_string() == malformed["id"]).unwrap();
+            assert_eq!(rejection.status, Status::PermFailed);
+            assert_eq!(rejection.to, vec![valid.from.clone()]);
+            assert!(rejection.body.contains("ParsingError"), "{}", rejection.body);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_do_not_block_the_others() {
+        let proxy = MockBeamProxy::start().await;
+        let client = BeamClient::new(&proxy.config()).unwrap();
+        let mut unknown_sender = serde_json::to_value(task(WORKFLOW, Duration::from_secs(10))).unwrap();
+        unknown_sender["from"] = "requester..broker.example.de".into();
+        let mut foreign_recipient = serde_json::to_value(task(WORKFLOW, Duration::from_secs(10))).unwrap();
+        foreign_recipient["to"].as_array_mut().unwrap().push("other_app.proxy3.broker.example.de".into());
+        proxy.enqueue_json(unknown_sender);
+        proxy.enqueue_json(foreign_recipient.clone());
+
+        let fetched = client.fetch_tasks().await.unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].id.to_string(), foreign_recipient["id"]);
+        assert_eq!(fetched[0].to, vec![app_id(EXECUTOR_APP)]);
+        // Without a valid sender there is no one to answer to
+        assert!(proxy.results().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetch_loop_reports_unsupported_executor() {
+        let proxy = MockBeamProxy::start().await;
+        let task = task(&WORKFLOW.replace("DockerExecutor", "HPCExecutor"), Duration::from_secs(10));
+        proxy.enqueue(task.clone());
+
+        let beam = Arc::new(BeamClient::new(&proxy.config()).unwrap());
+        let (tx, rx) = mpsc::channel(16);
+        let ledger = Arc::new(TaskLedger::load(None).unwrap());
+        let fetcher = tokio::spawn(fetch_beam_tasks(tx, beam.clone(), proxy.config(), ledger.clone()));
+        let handler = tokio::spawn(handle_tasks(rx, beam, ledger, proxy.config()));
+
+        let results = proxy.wait_for_results(Duration::from_secs(5), |r| r.len() >= 2).await;
+        fetcher.abort();
+        handler.abort();
+        assert_eq!(results[0].status, Status::Claimed);
+        assert_eq!(results[1].status, Status::PermFailed);
+        assert_eq!(results[1].task, task.id);
+        assert!(results[1].body.contains("TaskRejected"));
+        assert!(results[1].body.contains("available executors: DockerExecutor"));
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_are_retried_up_to_max_tries() {
+        let proxy = MockBeamProxy::start().await;
+        let task = BeamTask { failure_strategy: FailureStrategy::Retry(Retry { backoff_millisecs: 10, max_tries: 3 }), ..task(WORKFLOW, Duration::from_secs(10)) };
+        proxy.enqueue(task.clone());
+        let executor = Arc::new(FailingExecutor::default());
+        let mut registry = ExecutorRegistry::default();
+        registry.register("DockerExecutor", executor.clone());
+        let config = BeamConfig { executors: Arc::new(registry), ..proxy.config() };
+        let beam = BeamClient::new(&config).unwrap();
+        let task = ExecutionTask::parse(task, &config.catalog).unwrap();
+        execute_with_retries(task, &beam, &TaskLedger::load(None).unwrap(), &config).await;
+
+        let results = proxy.results();
+        let statuses: Vec<Status> = results.iter().map(|r| r.status.clone()).collect();
+        assert_eq!(statuses, vec![Status::TempFailed, Status::TempFailed, Status::PermFailed]);
+        let attempt: serde_json::Value = serde_json::from_str(&results[1].body).unwrap();
+        assert_eq!((&attempt["attempt"], &attempt["error"]), (&2.into(), &"StepFailed".into()));
+        let failure: serde_json::Value = serde_json::from_str(&results[2].body).unwrap();
+        assert_eq!(failure["attempts"].as_array().unwrap().len(), 3);
+        assert_eq!(executor.calls.lock().unwrap().iter().filter(|call| *call == "run").count(), 3);
+    }
+
+    #[tokio::test]
+    async fn timed_out_docker_step_is_not_retried() {
+        let proxy = MockBeamProxy::start().await;
+        let task = BeamTask { failure_strategy: FailureStrategy::Retry(Retry { backoff_millisecs: 10, max_tries: 3 }), ..task(WORKFLOW, Duration::from_secs(10)) };
+        proxy.enqueue(task.clone());
+        let timed_out = StepReport { name: "hello".into(), status: StepStatus::TimedOut, exit_code: None, logs: String::new() };
+        let executor = Arc::new(FailingExecutor { steps: vec![timed_out], ..Default::default() });
+        let mut registry = ExecutorRegistry::default();
+        registry.register("DockerExecutor", executor.clone());
+        let config = BeamConfig { executors: Arc::new(registry), ..proxy.config() };
+        let beam = BeamClient::new(&config).unwrap();
+        let task = ExecutionTask::parse(task, &config.catalog).unwrap();
+        execute_with_retries(task, &beam, &TaskLedger::load(None).unwrap(), &config).await;
+
+        let results = proxy.results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, Status::PermFailed);
+        let failure: serde_json::Value = serde_json::from_str(&results[0].body).unwrap();
+        assert_eq!(failure["error"], "TimedOut");
+        assert_eq!(executor.calls.lock().unwrap().iter().filter(|call| *call == "run").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn fetch_loop_receives_tasks_from_stream() {
+        let proxy = MockBeamProxy::start().await;
+        proxy.enable_streaming();
+        let beam = Arc::new(BeamClient::new(&proxy.config()).unwrap());
+        let mut tasks = beam.subscribe_tasks().await.unwrap();
+
+        let task = task(WORKFLOW, Duration::from_secs(10));
+        proxy.enqueue(task.clone());
+        let streamed = tokio::time::timeout(Duration::from_secs(5), tasks.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(streamed.id, task.id);
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let fetcher = tokio::spawn(fetch_beam_tasks(tx, beam, BeamConfig { task_stream: true, ..proxy.config() }, Arc::new(TaskLedger::load(None).unwrap())));
+        let forwarded = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        fetcher.abort();
+        assert_eq!(forwarded.context.id, task.id);
+        assert_eq!(proxy.results()[0].status, Status::Claimed);
+    }
+
+    #[tokio::test]
+    async fn fetch_loop_falls_back_to_polling() {
+        let proxy = MockBeamProxy::start().await;
+        let beam = Arc::new(BeamClient::new(&proxy.config()).unwrap());
+        assert!(matches!(beam.subscribe_tasks().await, Err(ExecutorError::StreamingUnsupported(_))));
+
+        let task = task(WORKFLOW, Duration::from_secs(10));
+        proxy.enqueue(task.clone());
+        let (tx, mut rx) = mpsc::channel(16);
+        let fetcher = tokio::spawn(fetch_beam_tasks(tx, beam, BeamConfig { task_stream: true, ..proxy.config() }, Arc::new(TaskLedger::load(None).unwrap())));
+        let forwarded = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        fetcher.abort();
+        assert_eq!(forwarded.context.id, task.id);
+    }
+}
@@ -0,0 +1,205 @@
+use std::{fmt::Display, str::FromStr};
+
+use tracing::warn;
+
+use crate::beam::AppId;
+
+/// AppIds that may send us workflow tasks, given as exact ids or wildcard patterns like `*.broker.example.de`.
+/// A `*` matches any (possibly empty) sequence of characters, including dots.
+#[derive(Debug, Clone, Default)]
+pub struct SenderAllowlist {
+    patterns: Option<Vec<String>>,
+}
+
+impl SenderAllowlist {
+    /// An empty list of patterns permits every sender.
+    pub fn new(patterns: Vec<String>) -> Self {
+        let patterns: Vec<String> = patterns
+            .into_iter()
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        if patterns.is_empty() {
+            warn!("No sender allowlist configured, accepting workflow tasks from every Beam app");
+            return SenderAllowlist { patterns: None };
+        }
+        SenderAllowlist { patterns: Some(patterns) }
+    }
+
+    pub fn permits(&self, sender: &AppId) -> bool {
+        let Some(patterns) = &self.patterns else {
+            return true;
+        };
+        let sender = sender.to_string().to_lowercase();
+        patterns.iter().any(|pattern| wildcard_match(pattern, &sender))
+    }
+}
+
+/// A container image reference, normalized like Docker does: `alpine` is `docker.io/library/alpine:latest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    /// Registry and path, e.g. `docker.io/library/alpine`
+    pub repository: String,
+    pub tag: Option<String>,
+    /// E.g. `sha256:` followed by 64 hex digits
+    pub digest: Option<String>,
+}
+
+impl FromStr for ImageRef {
+    type Err = String;
+
+    fn from_str(image: &str) -> Result<Self, Self::Err> {
+        let (name, digest) = match image.split_once('@') {
+            Some((name, digest)) => {
+                let valid = matches!(digest.strip_prefix("sha256:"), Some(hex) if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()));
+                if !valid {
+                    return Err(format!("{digest:?} is not a sha256 digest"));
+                }
+                (name, Some(digest.to_string()))
+            },
+            None => (image, None),
+        };
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag.to_string())),
+            _ => (name, None),
+        };
+        if name.is_empty() || name.split('/').any(str::is_empty) || name.contains(char::is_whitespace) {
+            return Err(format!("{image:?} is not a valid image reference"));
+        }
+        let (registry, path) = match name.split_once('/') {
+            Some((registry, path)) if registry.contains(['.', ':']) || registry == "localhost" => (registry.to_string(), path.to_string()),
+            _ => ("docker.io".to_string(), name.to_string()),
+        };
+        let path = if registry == "docker.io" && !path.contains('/') { format!("library/{path}") } else { path };
+        let tag = if digest.is_none() { tag.or_else(|| Some("latest".into())) } else { tag };
+        Ok(ImageRef { repository: format!("{}/{}", registry.to_lowercase(), path), tag, digest })
+    }
+}
+
+impl Display for ImageRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which container images may be run, given as repository patterns like `registry.example.de/*` or
+/// `docker.io/library/alpine`, matched against the normalized repository without tag or digest.
+#[derive(Debug, Clone, Default)]
+pub struct ImagePolicy {
+    patterns: Option<Vec<String>>,
+    /// Refuse images that are not referenced by digest
+    pub require_digest: bool,
+    /// Pin images referenced by tag to the digest they point to when the run starts
+    pub resolve_digests: bool,
+}
+
+impl ImagePolicy {
+    /// An empty list of patterns permits images from every registry.
+    pub fn new(patterns: Vec<String>, require_digest: bool, resolve_digests: bool) -> Self {
+        let patterns: Vec<String> = patterns
+            .into_iter()
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        if patterns.is_empty() {
+            warn!("No image allowlist configured, workflows may run images from every registry");
+        }
+        ImagePolicy { patterns: (!patterns.is_empty()).then_some(patterns), require_digest, resolve_digests }
+    }
+
+    /// Returns the parsed image if the policy permits it, or the reason why not.
+    pub fn check(&self, image: &str) -> Result<ImageRef, String> {
+        let reference: ImageRef = image.parse()?;
+        if let Some(patterns) = &self.patterns {
+            let repository = reference.repository.to_lowercase();
+            if !patterns.iter().any(|pattern| wildcard_match(pattern, &repository)) {
+                return Err(format!("{} is not an allowed repository", reference.repository));
+            }
+        }
+        if self.require_digest && reference.digest.is_none() {
+            return Err("images must be referenced by @sha256: digest".into());
+        }
+        Ok(reference)
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` stands for any sequence of characters.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::app_id;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*.broker.example.de", "app.proxy.broker.example.de"));
+        assert!(wildcard_match("app.*.broker.example.de", "app.proxy.broker.example.de"));
+        assert!(wildcard_match("app.proxy.broker.example.de", "app.proxy.broker.example.de"));
+        assert!(wildcard_match("*", "anything"));
+        assert!(!wildcard_match("*.broker.example.de", "app.proxy.broker.example.com"));
+        assert!(!wildcard_match("*.broker.example.de", "app.proxy.evilbroker.example.de"));
+        assert!(!wildcard_match("app.proxy.broker.example.de", "app.proxy.broker.example.de.evil"));
+        assert!(!wildcard_match("a*a", "a"));
+    }
+
+    #[test]
+    fn allowlist() {
+        let sender = app_id("app.proxy.broker.example.de");
+        assert!(SenderAllowlist::new(vec![]).permits(&sender));
+        assert!(SenderAllowlist::new(vec!["other.proxy.broker.example.de".into(), "*.Broker.example.de".into()]).permits(&sender));
+        assert!(!SenderAllowlist::new(vec!["*.proxy2.broker.example.de".into()]).permits(&sender));
+    }
+
+    #[test]
+    fn image_references_are_normalized() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let parse = |image: &str| image.parse::<ImageRef>().unwrap().to_string();
+        assert_eq!(parse("alpine"), "docker.io/library/alpine:latest");
+        assert_eq!(parse("samply/beam-proxy:main"), "docker.io/samply/beam-proxy:main");
+        assert_eq!(parse("localhost:5000/tools/r"), "localhost:5000/tools/r:latest");
+        assert_eq!(parse(&format!("Registry.Example.de/r@{digest}")), format!("registry.example.de/r@{digest}"));
+        assert!("alpine@sha256:123".parse::<ImageRef>().is_err());
+        assert!("registry.example.de//r".parse::<ImageRef>().is_err());
+    }
+
+    #[test]
+    fn image_policy() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let policy = ImagePolicy::new(vec!["registry.example.de/*".into(), "docker.io/library/alpine".into()], false, false);
+        assert!(policy.check("registry.example.de/stats/r:4.3").is_ok());
+        assert!(policy.check("alpine:3.18").is_ok());
+        assert!(policy.check("evil.example.com/alpine").is_err());
+        assert!(policy.check("alpinex").is_err());
+        assert!(ImagePolicy::new(vec![], false, false).check("anything.example.com/image").is_ok());
+
+        let pinned = ImagePolicy::new(vec![], true, false);
+        assert!(pinned.check("alpine:3.18").is_err());
+        assert!(pinned.check(&format!("alpine@{digest}")).is_ok());
+    }
+}
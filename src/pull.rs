@@ -0,0 +1,219 @@
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use base64::Engine;
+use bollard::{auth::DockerCredentials, image::CreateImageOptions, models::CreateImageInfo, Docker};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::{error::ExecutorError, policy::ImageRef};
+
+/// When images are pulled before a run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, clap::ValueEnum)]
+pub(crate) enum ImagePullPolicy {
+    /// Pull images that are not present locally
+    #[default]
+    Missing,
+    /// Pull every image before each run, e.g. to pick up tags that have moved
+    Always,
+    /// Never pull; images must have been pulled beforehand
+    Never,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PullSettings {
+    pub policy: ImagePullPolicy,
+    pub credentials: Arc<RegistryCredentials>,
+}
+
+/// An entry of a Docker `config.json`'s `auths`, or a per-registry credential file.
+#[derive(Deserialize)]
+struct Credential {
+    /// Base64 encoded `username:password`
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// The relevant part of a Docker `config.json`.
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, Credential>,
+    #[serde(rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(rename = "credHelpers", default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+/// Credentials for pulling images, by registry host.
+#[derive(Clone, Default)]
+pub(crate) struct RegistryCredentials {
+    registries: HashMap<String, DockerCredentials>,
+}
+
+impl std::fmt::Debug for RegistryCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Without the secrets
+        let mut registries: Vec<&String> = self.registries.keys().collect();
+        registries.sort();
+        f.debug_struct("RegistryCredentials").field("registries", &registries).finish()
+    }
+}
+
+impl RegistryCredentials {
+    /// Reads the `auths` of a Docker `config.json` and the files `<registry>.json` in `dir`, each holding `username` and
+    /// `password` or an `identitytoken`. Credentials from `dir` take precedence.
+    pub fn load(docker_config: Option<&Path>, dir: Option<&Path>) -> Result<Self, ExecutorError> {
+        let mut registries = HashMap::new();
+        if let Some(path) = docker_config {
+            let config: DockerConfig = serde_json::from_str(&read(path)?)
+                .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot parse Docker config {}: {e}", path.display())))?;
+            if config.creds_store.is_some() || !config.cred_helpers.is_empty() {
+                warn!("Credential helpers configured in {} are not supported, only credentials stored in the file are used", path.display());
+            }
+            for (address, credential) in config.auths {
+                if let Some(credentials) = credential.decode(&address)? {
+                    registries.insert(registry_host(&address), credentials);
+                }
+            }
+        }
+        if let Some(dir) = dir {
+            let read_error = |e: std::io::Error| ExecutorError::ConfigurationError(format!("Cannot read registry credentials {}: {e}", dir.display()));
+            for file in fs::read_dir(dir).map_err(read_error)? {
+                let path = file.map_err(read_error)?.path();
+                let (Some(registry), Some("json")) = (path.file_stem().and_then(|stem| stem.to_str()), path.extension().and_then(|ext| ext.to_str())) else {
+                    continue;
+                };
+                let credential: Credential = serde_json::from_str(&read(&path)?)
+                    .map_err(|e| ExecutorError::ConfigurationError(format!("Cannot parse registry credentials {}: {e}", path.display())))?;
+                let credentials = credential.decode(registry)?
+                    .ok_or_else(|| ExecutorError::ConfigurationError(format!("Registry credentials {} contain neither a password nor a token", path.display())))?;
+                registries.insert(registry_host(registry), credentials);
+            }
+        }
+        info!("Loaded credentials for {} registries", registries.len());
+        Ok(RegistryCredentials { registries })
+    }
+
+    /// Credentials for the registry `image` is pulled from, if any.
+    pub fn for_image(&self, image: &ImageRef) -> Option<DockerCredentials> {
+        let registry = image.repository.split('/').next().unwrap_or_default();
+        self.registries.get(registry).cloned()
+    }
+}
+
+impl Credential {
+    fn decode(self, registry: &str) -> Result<Option<DockerCredentials>, ExecutorError> {
+        let (username, password) = match self.auth.filter(|auth| !auth.is_empty()) {
+            Some(auth) => {
+                let decoded = base64::engine::general_purpose::STANDARD.decode(auth.trim()).ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())
+                    .and_then(|decoded| decoded.split_once(':').map(|(username, password)| (username.to_string(), password.to_string())))
+                    .ok_or_else(|| ExecutorError::ConfigurationError(format!("Credentials for registry {registry} are not base64 encoded username:password")))?;
+                (Some(decoded.0), Some(decoded.1))
+            },
+            None => (self.username, self.password),
+        };
+        if password.is_none() && self.identitytoken.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(DockerCredentials { username, password, identitytoken: self.identitytoken, serveraddress: Some(registry_host(registry)), ..Default::default() }))
+    }
+}
+
+fn read(path: &Path) -> Result<String, ExecutorError> {
+    fs::read_to_string(path).map_err(|e| ExecutorError::ConfigurationError(format!("Cannot read {}: {e}", path.display())))
+}
+
+/// The registry host of a credential entry like `https://index.docker.io/v1/`, named like in `ImageRef.repository`.
+fn registry_host(address: &str) -> String {
+    let address = address.trim_start_matches("https://").trim_start_matches("http://");
+    let host = address.split('/').next().unwrap_or_default().to_lowercase();
+    match host.as_str() {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io".into(),
+        _ => host,
+    }
+}
+
+/// Pulls exactly the referenced tag or digest; with an empty tag, Docker would pull every tag of the repository.
+fn pull_options(image: &ImageRef) -> CreateImageOptions<String> {
+    let tag = image.digest.clone().or_else(|| image.tag.clone()).unwrap_or_else(|| "latest".into());
+    CreateImageOptions { from_image: image.repository.clone(), tag, ..Default::default() }
+}
+
+/// Makes sure `image` is present locally, pulling it as `settings.policy` demands.
+pub(crate) async fn ensure_image(docker: &Docker, image: &str, settings: &PullSettings) -> Result<(), ExecutorError> {
+    match settings.policy {
+        ImagePullPolicy::Never => return Ok(()),
+        ImagePullPolicy::Missing if docker.inspect_image(image).await.is_ok() => {
+            debug!("Image {image} is present, not pulling it");
+            return Ok(());
+        },
+        _ => (),
+    }
+    let reference: ImageRef = image.parse().map_err(ExecutorError::InvalidWorkflow)?;
+    let credentials = settings.credentials.for_image(&reference);
+    info!("Pulling image {image}{}", if credentials.is_some() { " with credentials" } else { "" });
+    let mut progress = docker.create_image(Some(pull_options(&reference)), None, credentials);
+    while let Some(info) = progress.next().await {
+        let info = info.map_err(|e| ExecutorError::ImagePullFailed(format!("Cannot pull image {image}: {e}")))?;
+        if let Some(error) = info.error {
+            return Err(ExecutorError::ImagePullFailed(format!("Cannot pull image {image}: {error}")));
+        }
+        log_progress(image, &info);
+    }
+    info!("Pulled image {image}");
+    Ok(())
+}
+
+/// Logs changes of a layer's state; the progress bars of downloads and extractions only on debug level.
+fn log_progress(image: &str, info: &CreateImageInfo) {
+    let status = info.status.as_deref().unwrap_or_default();
+    let layer = info.id.as_deref().map(|id| format!(" {id}:")).unwrap_or_default();
+    match &info.progress {
+        Some(progress) => debug!("Pulling image {image}:{layer} {status} {progress}"),
+        None => info!("Pulling image {image}:{layer} {status}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn loads_credentials() {
+        let dir = TempDir::new("credentials");
+        fs::create_dir(dir.join("registries")).unwrap();
+        let hub = base64::engine::general_purpose::STANDARD.encode("hub-user:hub-secret");
+        fs::write(dir.join("config.json"), format!(r#"{{"auths": {{
+            "https://index.docker.io/v1/": {{"auth": "{hub}"}},
+            "https://registry.example.de": {{"username": "user", "password": "secret"}},
+            "helper.example.de": {{}}
+        }}, "credsStore": "desktop"}}"#)).unwrap();
+        fs::write(dir.join("registries").join("registry.example.de.json"), r#"{"identitytoken": "token"}"#).unwrap();
+        fs::write(dir.join("registries").join("README"), "not credentials").unwrap();
+        let credentials = RegistryCredentials::load(Some(&dir.join("config.json")), Some(&dir.join("registries")));
+        drop(dir);
+
+        let credentials = credentials.unwrap();
+        let hub = credentials.for_image(&"alpine:3".parse().unwrap()).unwrap();
+        assert_eq!((hub.username.as_deref(), hub.password.as_deref()), (Some("hub-user"), Some("hub-secret")));
+        let registry = credentials.for_image(&"registry.example.de/analysis/count".parse().unwrap()).unwrap();
+        assert_eq!((registry.identitytoken.as_deref(), registry.password), (Some("token"), None));
+        assert!(credentials.for_image(&"helper.example.de/count".parse().unwrap()).is_none());
+        assert!(!format!("{credentials:?}").contains("secret"));
+    }
+
+    #[test]
+    fn pulls_exactly_the_referenced_image() {
+        let options = pull_options(&"registry.example.de:5000/count".parse().unwrap());
+        assert_eq!((options.from_image.as_str(), options.tag.as_str()), ("registry.example.de:5000/count", "latest"));
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let options = pull_options(&format!("alpine:3@{digest}").parse().unwrap());
+        assert_eq!((options.from_image.as_str(), options.tag), ("docker.io/library/alpine", digest));
+        assert_eq!(registry_host("https://Registry.example.de:5000/v2/"), "registry.example.de:5000");
+    }
+}
@@ -0,0 +1,114 @@
+use schemars::{gen::SchemaGenerator, schema::{InstanceType, NumberValidation, Schema, SchemaObject}, JsonSchema};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use serde_json::Value;
+
+use crate::format::{parse_document, Format};
+
+/// Version of the task body schema this orchestrator reads natively. Older versions are upgraded on parsing.
+pub(crate) const CURRENT_SCHEMA_VERSION: u32 = 2;
+
+/// Upgrades a document from version `i + 1` to version `i + 2`.
+const UPGRADES: [fn(&mut Value); (CURRENT_SCHEMA_VERSION - 1) as usize] = [upgrade_v1];
+
+/// Version 2 names the executor directly: `"executor": {"name": "DockerExecutor"}` becomes `"executor": "DockerExecutor"`.
+fn upgrade_v1(document: &mut Value) {
+    if let Some(executor) = document.get_mut("executor") {
+        if let Some(name) = executor.get("name").cloned() {
+            *executor = name;
+        }
+    }
+}
+
+/// The `schema_version` field of a parsed document, which `parse_versioned` has already checked and upgraded to
+/// `CURRENT_SCHEMA_VERSION`. It only exists to describe the field in the published schema.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SchemaVersion;
+
+impl<'de> Deserialize<'de> for SchemaVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(|_| SchemaVersion)
+    }
+}
+
+impl JsonSchema for SchemaVersion {
+    fn schema_name() -> String {
+        "SchemaVersion".into()
+    }
+
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::Integer.into()),
+            number: Some(Box::new(NumberValidation { minimum: Some(1.0), maximum: Some(CURRENT_SCHEMA_VERSION.into()), ..Default::default() })),
+            ..Default::default()
+        }.into()
+    }
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    #[serde(default = "first_version")]
+    schema_version: u32,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+/// Parses a task body or catalog file, upgrading documents written against older schema versions first.
+/// Documents in the current version are parsed directly, so errors keep their line and column.
+pub(crate) fn parse_versioned<T: DeserializeOwned>(text: &str, format: Format) -> Result<T, String> {
+    let Versioned { schema_version } = parse_document(text, format)?;
+    if schema_version == CURRENT_SCHEMA_VERSION {
+        return parse_document(text, format);
+    }
+    if schema_version == 0 || schema_version > CURRENT_SCHEMA_VERSION {
+        return Err(format!("unsupported schema_version {schema_version}, this site supports versions 1 to {CURRENT_SCHEMA_VERSION}"));
+    }
+    let mut document: Value = parse_document(text, format)?;
+    for upgrade in &UPGRADES[(schema_version - 1) as usize..] {
+        upgrade(&mut document);
+    }
+    if let Value::Object(fields) = &mut document {
+        fields.insert("schema_version".into(), CURRENT_SCHEMA_VERSION.into());
+    }
+    serde_json::from_value(document).map_err(|e| format!("invalid document after upgrading from schema_version {schema_version}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, path::Path};
+
+    use super::*;
+    use crate::workflow::TaskBody;
+
+    /// JSON Schema of the current task body, published for requester tooling.
+    const SCHEMA_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/schema/task-body.schema.json");
+
+    #[test]
+    fn old_versions_are_upgraded() {
+        let v1: Value = parse_versioned(r#"{"executor":{"name":"DockerExecutor"},"workflow":null}"#, Format::Json).unwrap();
+        assert_eq!(v1["executor"], "DockerExecutor");
+        assert_eq!(v1["schema_version"], CURRENT_SCHEMA_VERSION);
+        let v2: Value = parse_versioned("schema_version: 2\nexecutor: DockerExecutor\n", Format::Yaml).unwrap();
+        assert_eq!(v2["executor"], "DockerExecutor");
+        let unknown = parse_versioned::<BTreeMap<String, Value>>(r#"{"schema_version":99}"#, Format::Json).unwrap_err();
+        assert!(unknown.contains("unsupported schema_version 99"), "{unknown}");
+    }
+
+    /// Set `BK_UPDATE_SCHEMA=1` to rewrite the published schema after changing the task body types. The test still
+    /// fails on that run, so an outdated schema cannot pass unnoticed; commit the rewritten file and run it again.
+    #[test]
+    fn published_schema_is_up_to_date() {
+        let schema = serde_json::to_string_pretty(&schemars::schema_for!(TaskBody)).unwrap() + "\n";
+        let published = std::fs::read_to_string(SCHEMA_FILE).unwrap_or_default();
+        if published != schema && std::env::var_os("BK_UPDATE_SCHEMA").is_some() {
+            std::fs::create_dir_all(Path::new(SCHEMA_FILE).parent().unwrap()).unwrap();
+            std::fs::write(SCHEMA_FILE, &schema).unwrap();
+        }
+        assert!(published == schema, "{SCHEMA_FILE} is outdated, run the tests with BK_UPDATE_SCHEMA=1 to update it");
+    }
+}
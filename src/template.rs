@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{validation::ValidationError, workflow::Workflow};
+
+/// A value a workflow can be instantiated with, referenced as `${name}` in step fields.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct Parameter {
+    #[serde(flatten)]
+    pub kind: ParameterType,
+    /// Used when the task supplies no value; parameters without a default are required
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum ParameterType {
+    String,
+    Integer,
+    Boolean,
+    /// One of a fixed set of strings
+    Enum { values: Vec<String> },
+}
+
+impl ParameterType {
+    /// Renders `value` for substitution if it has this type.
+    fn render(&self, value: &Value) -> Result<String, String> {
+        match (self, value) {
+            (ParameterType::String, Value::String(s)) if s.chars().any(char::is_control) => Err("must not contain control characters".into()),
+            (ParameterType::String, Value::String(s)) => Ok(s.clone()),
+            (ParameterType::Integer, Value::Number(n)) if n.is_i64() || n.is_u64() => Ok(n.to_string()),
+            (ParameterType::Boolean, Value::Bool(b)) => Ok(b.to_string()),
+            (ParameterType::Enum { values }, Value::String(s)) if values.contains(s) => Ok(s.clone()),
+            (ParameterType::Enum { values }, _) => Err(format!("must be one of {}", values.join(", "))),
+            (ParameterType::String, _) => Err("must be a string".into()),
+            (ParameterType::Integer, _) => Err("must be an integer".into()),
+            (ParameterType::Boolean, _) => Err("must be a boolean".into()),
+        }
+    }
+}
+
+/// Replaces `${name}` with the parameter's value and `$$` with `$`; any other `$` is kept as is.
+fn substitute(template: &str, values: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| format!("unterminated reference in {template:?}"))?;
+            let name = &after[..end];
+            let value = values.get(name).ok_or_else(|| format!("{name:?} is not a declared parameter"))?;
+            result.push_str(value);
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Checks the supplied `values` against the workflow's declared parameters and substitutes them into the steps'
+/// `image`, `env`, `input` and `output` fields. The instance declares no parameters anymore, so it is not substituted
+/// twice. Workflows declaring no parameters are returned unchanged, so that their fields may contain `${...}` literally.
+pub(crate) fn instantiate(workflow: Workflow, values: &BTreeMap<String, Value>) -> Result<Workflow, Vec<ValidationError>> {
+    let mut errors = Vec::new();
+    for name in values.keys().filter(|name| !workflow.parameters.contains_key(*name)) {
+        errors.push(ValidationError::UnknownParameter { parameter: name.clone() });
+    }
+    if workflow.parameters.is_empty() {
+        return if errors.is_empty() { Ok(workflow) } else { Err(errors) };
+    }
+
+    let mut rendered = BTreeMap::new();
+    for (name, parameter) in &workflow.parameters {
+        let Some(value) = values.get(name).or(parameter.default.as_ref()) else {
+            errors.push(ValidationError::MissingParameter { parameter: name.clone() });
+            continue;
+        };
+        match parameter.kind.render(value) {
+            Ok(value) => {
+                rendered.insert(name.clone(), value);
+            },
+            Err(detail) => errors.push(ValidationError::InvalidParameterValue { parameter: name.clone(), detail }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut instance = workflow;
+    instance.parameters.clear();
+    for step in &mut instance.steps {
+        let name = step.name.clone();
+        let mut render = |field: &'static str, template: &mut String| {
+            match substitute(template, &rendered) {
+                Ok(value) => *template = value,
+                Err(detail) => errors.push(ValidationError::InvalidTemplate { step: name.clone(), field: field.into(), detail }),
+            }
+        };
+        render("image", &mut step.image);
+        for entry in step.env.iter_mut().flatten() {
+            render("env", entry);
+        }
+        for input in step.input.iter_mut().flatten() {
+            render("input", input);
+        }
+        render("output", &mut step.output);
+    }
+    if errors.is_empty() {
+        Ok(instance)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn workflow() -> Workflow {
+        serde_json::from_value(json!({
+            "output": ["report.csv"],
+            "parameters": {
+                "version": {"type": "string", "default": "1.0"},
+                "year": {"type": "integer"},
+                "verbose": {"type": "boolean", "default": false},
+                "format": {"type": "enum", "values": ["csv", "json"], "default": "csv"}
+            },
+            "steps": [{
+                "name": "report",
+                "image": "registry.example.de/report:${version}",
+                "env": ["YEAR=${year}", "VERBOSE=${verbose}", "PRICE=$$5", "HOME=$HOME"],
+                "input": null,
+                "output": "report.${format}"
+            }]
+        })).unwrap()
+    }
+
+    fn values(values: Value) -> BTreeMap<String, Value> {
+        serde_json::from_value(values).unwrap()
+    }
+
+    #[test]
+    fn substitutes_values_and_defaults() {
+        let instance = instantiate(workflow(), &values(json!({"year": 2023, "verbose": true}))).unwrap();
+        let step = &instance.steps[0];
+        assert_eq!(step.image, "registry.example.de/report:1.0");
+        assert_eq!(step.env.as_deref().unwrap(), ["YEAR=2023", "VERBOSE=true", "PRICE=$5", "HOME=$HOME"]);
+        assert_eq!(step.output, "report.csv");
+    }
+
+    #[test]
+    fn reports_all_invalid_values() {
+        let errors = instantiate(workflow(), &values(json!({"format": "xml", "verbose": "yes", "colour": "red"}))).unwrap_err();
+        assert_eq!(errors, vec![
+            ValidationError::UnknownParameter { parameter: "colour".into() },
+            ValidationError::InvalidParameterValue { parameter: "format".into(), detail: "must be one of csv, json".into() },
+            ValidationError::InvalidParameterValue { parameter: "verbose".into(), detail: "must be a boolean".into() },
+            ValidationError::MissingParameter { parameter: "year".into() },
+        ]);
+    }
+
+    #[test]
+    fn rejects_undeclared_references() {
+        let mut workflow = workflow();
+        workflow.steps[0].output = "${outfile".into();
+        workflow.steps[0].input = Some(vec!["${secret}".into()]);
+        let errors = instantiate(workflow, &values(json!({"year": 2023}))).unwrap_err();
+        assert_eq!(errors, vec![
+            ValidationError::InvalidTemplate { step: "report".into(), field: "input".into(), detail: "\"secret\" is not a declared parameter".into() },
+            ValidationError::InvalidTemplate { step: "report".into(), field: "output".into(), detail: "unterminated reference in \"${outfile\"".into() },
+        ]);
+    }
+
+    #[test]
+    fn workflows_without_parameters_are_unchanged() {
+        let mut workflow = workflow();
+        workflow.parameters.clear();
+        let instance = instantiate(workflow.clone(), &BTreeMap::new()).unwrap();
+        assert_eq!(instance.steps[0].image, workflow.steps[0].image);
+        assert!(instantiate(workflow, &values(json!({"year": 1}))).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Test helpers, most notably an in-process stand-in for the Beam proxy.
+
+use std::{convert::Infallible, net::SocketAddr, ops::Deref, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{sse::{Event, Sse}, IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use futures_util::{stream, Stream};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{sync::Semaphore, task::JoinHandle, time::{sleep, timeout, Instant}};
+use uuid::Uuid;
+
+use crate::{
+    beam::{AppId, BeamResult, BeamTask, FailureStrategy, Retry},
+    catalog::Catalog,
+    config::{prepare_reqwest_client, BeamConfig},
+    docker_executor::DelegateProfile,
+    error::ExecutorError,
+    executor::{Capabilities, Executor, ExecutorRegistry, Run},
+    hpc_executor::SlurmSettings,
+    local_executor::LocalSettings,
+    policy::SenderAllowlist,
+    pull::PullSettings,
+    workflow::{SiteLimits, StepFailurePolicy, StepReport},
+};
+
+pub const EXECUTOR_APP: &str = "executor.proxy1.broker.example.de";
+pub const REQUESTER_APP: &str = "requester.proxy2.broker.example.de";
+pub const API_KEY: &str = "SecretApiKey";
+
+/// A minimal single-step workflow for the Docker executor.
+pub const WORKFLOW: &str = r#"{"executor":{"name":"DockerExecutor"},"workflow":{"output":["out.csv"],"steps":[{"name":"s","image":"i","env":null,"input":null,"output":"out.csv"}]}}"#;
+
+pub fn app_id(id: &str) -> AppId {
+    AppId::new(id.into()).unwrap()
+}
+
+/// Configuration for `EXECUTOR_APP` talking to a proxy at `url`, accepting tasks from every sender.
+pub fn test_config(url: &str) -> BeamConfig {
+    let mut config = BeamConfig {
+        app_id: app_id(EXECUTOR_APP),
+        app_key: API_KEY.into(),
+        beam_proxy_url: url.parse().unwrap(),
+        client: prepare_reqwest_client(&Vec::new()).unwrap(),
+        task_stream: false,
+        allowed_senders: SenderAllowlist::default(),
+        state_file: None,
+        docker_delegate: false,
+        delegate: DelegateProfile { image: "orchestrator-tester:local".into(), command: None, entrypoint: None, env: Vec::new(), working_dir: None, tty: true, stdin: true },
+        delegate_profiles: Default::default(),
+        step_slots: Arc::new(Semaphore::new(4)),
+        on_step_failure: StepFailurePolicy::Cancel,
+        limits: SiteLimits::default(),
+        pull: PullSettings::default(),
+        catalog: Arc::new(Catalog::load(None, true).unwrap()),
+        slurm: SlurmSettings::default(),
+        local: LocalSettings::default(),
+        executors: Arc::default(),
+    };
+    config.executors = Arc::new(ExecutorRegistry::load(&["DockerExecutor".into()], &config).unwrap());
+    config
+}
+
+/// A task from `REQUESTER_APP` to `EXECUTOR_APP` that is tried once.
+pub fn test_task(body: &str, ttl: Duration) -> BeamTask {
+    BeamTask {
+        id: Uuid::new_v4(),
+        from: app_id(REQUESTER_APP),
+        to: vec![app_id(EXECUTOR_APP)],
+        metadata: "test".into(),
+        body: body.into(),
+        ttl,
+        failure_strategy: FailureStrategy::Retry(Retry { backoff_millisecs: 10, max_tries: 1 }),
+        received: Instant::now(),
+    }
+}
+
+/// A new directory in the system's temp directory, removed with all its contents when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates `bk-orchestrator-{name}-{uuid}`, so that concurrently running tests do not share directories.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("bk-orchestrator-{name}-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Records the calls it receives and fails in `run`, reporting `steps` as the steps that did not succeed.
+#[derive(Debug, Default)]
+pub struct FailingExecutor {
+    pub calls: Mutex<Vec<String>>,
+    pub steps: Vec<StepReport>,
+}
+
+#[async_trait]
+impl Executor for FailingExecutor {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    async fn prepare(&self, run: &Run<'_>) -> Result<(), ExecutorError> {
+        self.calls.lock().unwrap().push(format!("prepare {}", run.name));
+        Ok(())
+    }
+
+    async fn run(&self, _run: &Run<'_>) -> Result<(), ExecutorError> {
+        self.calls.lock().unwrap().push("run".into());
+        Err(ExecutorError::from_steps(&self.steps))
+    }
+
+    async fn collect_outputs(&self, _run: &Run<'_>) -> Result<String, ExecutorError> {
+        self.calls.lock().unwrap().push("collect_outputs".into());
+        Ok(String::new())
+    }
+
+    async fn cancel(&self, _run: &Run<'_>) {
+        self.calls.lock().unwrap().push("cancel".into());
+    }
+}
+
+/// Tasks are kept as the JSON they were enqueued as, so that malformed ones can be delivered as well.
+#[derive(Default)]
+struct ProxyState {
+    tasks: Vec<Value>,
+    results: Vec<(String, BeamResult)>,
+    streaming: bool,
+}
+
+fn task_id(task: &Value) -> Option<Uuid> {
+    task.get("id").and_then(|id| Uuid::deserialize(id).ok())
+}
+
+fn is_addressed_to(task: &Value, app: &str) -> bool {
+    task["to"].as_array().into_iter().flatten().any(|to| to.as_str() == Some(app))
+}
+
+impl ProxyState {
+    fn todo_for(&self, app: &str) -> Vec<Value> {
+        self.tasks
+            .iter()
+            .filter(|t| is_addressed_to(t, app))
+            .filter(|t| !self.results.iter().any(|(from, r)| Some(r.task) == task_id(t) && from == app))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Serves `/v1/health`, `/v1/tasks` and `/v1/tasks/{id}/results/{app}` like a Beam proxy would,
+/// keeping tasks and results in memory. The server stops when the proxy is dropped.
+pub struct MockBeamProxy {
+    addr: SocketAddr,
+    state: Arc<Mutex<ProxyState>>,
+    server: JoinHandle<()>,
+}
+
+impl MockBeamProxy {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(ProxyState::default()));
+        let app = Router::new()
+            .route("/v1/health", get(|| async { StatusCode::OK }))
+            .route("/v1/tasks", get(get_tasks))
+            .route("/v1/tasks/:task_id/results/:app_id", put(put_result))
+            .with_state(state.clone());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+        let addr = server.local_addr();
+        let server = tokio::spawn(async move {
+            server.await.expect("Mock Beam proxy failed");
+        });
+        MockBeamProxy { addr, state, server }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /// Configuration for `EXECUTOR_APP` talking to this proxy.
+    pub fn config(&self) -> BeamConfig {
+        test_config(&self.url())
+    }
+
+    /// Queues a task for delivery. Enqueuing a task again re-delivers it, even if it has been answered.
+    pub fn enqueue(&self, task: BeamTask) {
+        self.enqueue_json(serde_json::to_value(task).unwrap());
+    }
+
+    /// Queues a task given as JSON, which need not be a valid `BeamTask`.
+    pub fn enqueue_json(&self, task: Value) {
+        let id = task_id(&task);
+        let mut state = self.state.lock().unwrap();
+        state.tasks.retain(|t| task_id(t) != id);
+        state.results.retain(|(_, r)| Some(r.task) != id);
+        state.tasks.push(task);
+    }
+
+    /// Serve `GET /v1/tasks` as Server-Sent Events to clients asking for `text/event-stream`.
+    pub fn enable_streaming(&self) {
+        self.state.lock().unwrap().streaming = true;
+    }
+
+    pub fn results(&self) -> Vec<BeamResult> {
+        self.state.lock().unwrap().results.iter().map(|(_, r)| r.clone()).collect()
+    }
+
+    /// Waits until `predicate` holds for the received results, panicking after `max_wait`.
+    pub async fn wait_for_results(&self, max_wait: Duration, predicate: impl Fn(&[BeamResult]) -> bool) -> Vec<BeamResult> {
+        timeout(max_wait, async {
+            loop {
+                let results = self.results();
+                if predicate(&results) {
+                    return results;
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Expected results did not arrive, got {:?}", self.results()))
+    }
+}
+
+impl Drop for MockBeamProxy {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+fn authorized_app(headers: &HeaderMap) -> Result<String, StatusCode> {
+    let auth = headers
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    match auth.split(' ').collect::<Vec<_>>()[..] {
+        ["ApiKey", app, key] if key == API_KEY => Ok(app.to_string()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[derive(Deserialize)]
+struct TaskQuery {
+    filter: Option<String>,
+}
+
+async fn get_tasks(
+    State(state): State<Arc<Mutex<ProxyState>>>,
+    Query(query): Query<TaskQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let app = authorized_app(&headers)?;
+    let wants_stream = headers.get("accept").and_then(|h| h.to_str().ok()) == Some("text/event-stream");
+    if wants_stream && state.lock().unwrap().streaming {
+        return Ok(stream_tasks(state, app).into_response());
+    }
+    // Mimic long polling without holding up the tests for too long
+    let deadline = Instant::now() + Duration::from_millis(200);
+    loop {
+        let tasks: Vec<Value> = {
+            let state = state.lock().unwrap();
+            if query.filter.as_deref() == Some("todo") {
+                state.todo_for(&app)
+            } else {
+                state.tasks.iter().filter(|t| is_addressed_to(t, &app)).cloned().collect()
+            }
+        };
+        if !tasks.is_empty() || Instant::now() >= deadline {
+            return Ok(Json(tasks).into_response());
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Sends every open task once per connection, as soon as it shows up.
+fn stream_tasks(state: Arc<Mutex<ProxyState>>, app: String) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = stream::unfold((state, app, Vec::<Value>::new()), |(state, app, mut sent)| async move {
+        loop {
+            let next = state.lock().unwrap().todo_for(&app).into_iter().find(|t| !sent.contains(t));
+            if let Some(task) = next {
+                let event = Event::default().data(task.to_string());
+                sent.push(task);
+                return Some((Ok(event), (state, app, sent)));
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    });
+    Sse::new(events)
+}
+
+async fn put_result(
+    State(state): State<Arc<Mutex<ProxyState>>>,
+    Path((task_id, app_id)): Path<(Uuid, String)>,
+    headers: HeaderMap,
+    Json(result): Json<BeamResult>,
+) -> StatusCode {
+    match authorized_app(&headers) {
+        Ok(app) if app == app_id => (),
+        _ => return StatusCode::UNAUTHORIZED,
+    }
+    let mut state = state.lock().unwrap();
+    if !state.tasks.iter().any(|t| self::task_id(t) == Some(task_id)) {
+        return StatusCode::NOT_FOUND;
+    }
+    let first = !state.results.iter().any(|(from, r)| r.task == task_id && *from == app_id);
+    state.results.push((app_id, result));
+    if first {
+        StatusCode::CREATED
+    } else {
+        StatusCode::NO_CONTENT
+    }
+}
@@ -0,0 +1,285 @@
+use std::{collections::HashMap, fmt::Display, time::Duration};
+
+use serde::Serialize;
+
+use crate::{dag::WorkflowGraph, policy::ImagePolicy, workflow::{SiteLimits, Workflow}};
+
+/// A problem with a workflow that would make it fail during execution.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum ValidationError {
+    NoSteps,
+    InvalidStepName { step: String },
+    DuplicateStepName { step: String },
+    EmptyImage { step: String },
+    ImageNotAllowed { step: String, image: String, reason: String },
+    InvalidEnv { step: String, entry: String },
+    InvalidFileName { step: Option<String>, file: String },
+    InvalidResource { step: String, resource: String },
+    ResourceLimitExceeded { step: String, resource: String, requested: String, limit: String },
+    InvalidTimeout { step: Option<String> },
+    TimeoutExceeded { step: Option<String>, requested: String, limit: String },
+    DuplicateOutput { output: String, steps: Vec<String> },
+    MissingInput { step: String, input: String },
+    MissingWorkflowOutput { output: String },
+    Cycle { detail: String },
+    UnknownParameter { parameter: String },
+    MissingParameter { parameter: String },
+    InvalidParameterValue { parameter: String, detail: String },
+    InvalidTemplate { step: String, field: String, detail: String },
+    UnknownCatalogWorkflow { name: String, version: String },
+    UnsupportedFeature { executor: String, feature: String },
+    CommandNotAllowed { step: String, command: String },
+    EnvNotAllowed { step: String, variable: String },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::NoSteps => write!(f, "workflow has no steps"),
+            ValidationError::InvalidStepName { step } => write!(f, "step name {step:?} may only contain letters, digits, '_', '.' and '-' and must start with a letter or digit"),
+            ValidationError::DuplicateStepName { step } => write!(f, "step name {step} is used more than once"),
+            ValidationError::EmptyImage { step } => write!(f, "step {step} has no image"),
+            ValidationError::ImageNotAllowed { step, image, reason } => write!(f, "image {image} of step {step} is not allowed: {reason}"),
+            ValidationError::InvalidEnv { step, entry } => write!(f, "environment entry {entry:?} of step {step} is not of the form KEY=value"),
+            ValidationError::InvalidFileName { step: Some(step), file } => write!(f, "file name {file:?} of step {step} is not a plain file name"),
+            ValidationError::InvalidFileName { step: None, file } => write!(f, "workflow output {file:?} is not a plain file name"),
+            ValidationError::InvalidResource { step, resource } => write!(f, "{resource} limit of step {step} must be positive"),
+            ValidationError::ResourceLimitExceeded { step, resource, requested, limit } => write!(f, "step {step} requests {requested} {resource}, but at most {limit} are allowed"),
+            ValidationError::InvalidTimeout { step: Some(step) } => write!(f, "timeout of step {step} must be positive"),
+            ValidationError::InvalidTimeout { step: None } => write!(f, "workflow timeout must be positive"),
+            ValidationError::TimeoutExceeded { step: Some(step), requested, limit } => write!(f, "step {step} requests a timeout of {requested}, but at most {limit} is allowed"),
+            ValidationError::TimeoutExceeded { step: None, requested, limit } => write!(f, "workflow requests a timeout of {requested}, but at most {limit} is allowed"),
+            ValidationError::DuplicateOutput { output, steps } => write!(f, "{output} is produced by more than one step: {}", steps.join(", ")),
+            ValidationError::MissingInput { step, input } => write!(f, "no step produces {input}, the input of step {step}"),
+            ValidationError::MissingWorkflowOutput { output } => write!(f, "no step produces the workflow output {output}"),
+            ValidationError::Cycle { detail } => write!(f, "{detail}"),
+            ValidationError::UnknownParameter { parameter } => write!(f, "workflow declares no parameter {parameter}"),
+            ValidationError::MissingParameter { parameter } => write!(f, "parameter {parameter} has no value and no default"),
+            ValidationError::InvalidParameterValue { parameter, detail } => write!(f, "parameter {parameter} {detail}"),
+            ValidationError::InvalidTemplate { step, field, detail } => write!(f, "{field} of step {step}: {detail}"),
+            ValidationError::UnknownCatalogWorkflow { name, version } => write!(f, "the catalog has no workflow {name} in version {version}"),
+            ValidationError::UnsupportedFeature { executor, feature } => write!(f, "{executor} does not support {feature}"),
+            ValidationError::CommandNotAllowed { step, command } => write!(f, "command {command} of step {step} is not allowed at this site"),
+            ValidationError::EnvNotAllowed { step, variable } => write!(f, "environment variable {variable} of step {step} may not be set at this site"),
+        }
+    }
+}
+
+/// Step names end up in container names, so they are restricted to what Docker accepts there.
+fn is_valid_step_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Files are exchanged in a single shared directory, so their names must not contain paths.
+fn is_valid_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+/// Checks a timeout against its maximum.
+fn check_timeout(step: Option<&str>, timeout: Option<Duration>, max: Option<Duration>) -> Option<ValidationError> {
+    let step = step.map(str::to_string);
+    match (timeout, max) {
+        (Some(timeout), _) if timeout.is_zero() => Some(ValidationError::InvalidTimeout { step }),
+        (Some(timeout), Some(max)) if timeout > max => Some(ValidationError::TimeoutExceeded { step, requested: format!("{timeout:?}"), limit: format!("{max:?}") }),
+        _ => None,
+    }
+}
+
+/// Checks the steps' images against the site's image policy. Only applies to executors running the steps as
+/// container images, others interpret `WorkflowSteps.image` differently.
+pub(crate) fn check_images(workflow: &Workflow, policy: &ImagePolicy) -> Vec<ValidationError> {
+    workflow.steps.iter()
+        .filter(|step| !step.image.trim().is_empty())
+        .filter_map(|step| policy.check(&step.image).err().map(|reason| ValidationError::ImageNotAllowed { step: step.name.clone(), image: step.image.clone(), reason }))
+        .collect()
+}
+
+/// Checks a workflow for every problem that would make it fail during execution or exceed the site's `limits`
+/// and reports all of them at once. Images are checked by the executors, see `check_images`.
+pub(crate) fn validate_workflow(workflow: &Workflow, limits: &SiteLimits) -> Result<(), Vec<ValidationError>> {
+    let mut errors = Vec::new();
+    if workflow.steps.is_empty() {
+        errors.push(ValidationError::NoSteps);
+    }
+    errors.extend(check_timeout(None, workflow.timeout, limits.max_workflow_timeout));
+
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    let mut producers: HashMap<&str, Vec<String>> = HashMap::new();
+    for step in &workflow.steps {
+        if !is_valid_step_name(&step.name) {
+            errors.push(ValidationError::InvalidStepName { step: step.name.clone() });
+        }
+        let count = names.entry(&step.name).or_default();
+        *count += 1;
+        if *count == 2 {
+            errors.push(ValidationError::DuplicateStepName { step: step.name.clone() });
+        }
+        if step.image.trim().is_empty() {
+            errors.push(ValidationError::EmptyImage { step: step.name.clone() });
+        }
+        for entry in step.env.iter().flatten() {
+            if !matches!(entry.split_once('='), Some((key, _)) if !key.is_empty()) {
+                errors.push(ValidationError::InvalidEnv { step: step.name.clone(), entry: entry.clone() });
+            }
+        }
+        for file in step.inputs().iter().chain(std::iter::once(&step.output)) {
+            if !is_valid_file_name(file) {
+                errors.push(ValidationError::InvalidFileName { step: Some(step.name.clone()), file: file.clone() });
+            }
+        }
+        if let Some(resources) = &step.resources {
+            for resource in resources.invalid() {
+                errors.push(ValidationError::InvalidResource { step: step.name.clone(), resource: resource.into() });
+            }
+            for (resource, requested, limit) in resources.exceeding(&limits.resources) {
+                errors.push(ValidationError::ResourceLimitExceeded { step: step.name.clone(), resource: resource.into(), requested, limit });
+            }
+        }
+        errors.extend(check_timeout(Some(&step.name), step.timeout, limits.max_step_timeout));
+        producers.entry(&step.output).or_default().push(step.name.clone());
+    }
+
+    let mut duplicates: Vec<_> = producers.iter().filter(|(_, steps)| steps.len() > 1).collect();
+    duplicates.sort();
+    for (output, steps) in duplicates {
+        errors.push(ValidationError::DuplicateOutput { output: output.to_string(), steps: steps.clone() });
+    }
+    for step in &workflow.steps {
+        for input in step.inputs() {
+            if !producers.contains_key(input.as_str()) {
+                errors.push(ValidationError::MissingInput { step: step.name.clone(), input: input.clone() });
+            }
+        }
+    }
+    for output in &workflow.output {
+        if !is_valid_file_name(output) {
+            errors.push(ValidationError::InvalidFileName { step: None, file: output.clone() });
+        } else if !producers.contains_key(output.as_str()) {
+            errors.push(ValidationError::MissingWorkflowOutput { output: output.clone() });
+        }
+    }
+
+    // Cycles can only be told apart from the problems above once those are fixed
+    if errors.is_empty() {
+        if let Err(e) = WorkflowGraph::build(workflow) {
+            errors.push(ValidationError::Cycle { detail: e.to_string() });
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workflow::{StepResources, WorkflowSteps};
+
+    fn step(name: &str, input: &[&str], output: &str) -> WorkflowSteps {
+        WorkflowSteps {
+            name: name.into(),
+            image: format!("{name}:latest"),
+            env: Some(vec!["KEY=value".into()]),
+            input: Some(input.iter().map(|i| i.to_string()).collect()),
+            output: output.into(),
+            resources: None,
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn accepts_valid_workflow() {
+        let workflow = Workflow {
+            output: vec!["merged.csv".into()],
+            steps: vec![step("import", &[], "data.csv"), step("merge", &["data.csv"], "merged.csv")],
+            max_parallelism: None,
+            timeout: None,
+            parameters: Default::default(),
+        };
+        assert_eq!(validate_workflow(&workflow, &SiteLimits::default()), Ok(()));
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let mut broken = step("import", &[], "data.csv");
+        broken.env = Some(vec!["NOVALUE".into()]);
+        let workflow = Workflow {
+            output: vec!["result.csv".into(), "../etc/passwd".into()],
+            steps: vec![
+                broken,
+                step("import", &[], "data.csv"),
+                step("merge", &["data.csv", "other.csv"], "merged.csv"),
+            ],
+            max_parallelism: None,
+            timeout: None,
+            parameters: Default::default(),
+        };
+        let errors = validate_workflow(&workflow, &SiteLimits::default()).unwrap_err();
+        assert_eq!(errors, vec![
+            ValidationError::InvalidEnv { step: "import".into(), entry: "NOVALUE".into() },
+            ValidationError::DuplicateStepName { step: "import".into() },
+            ValidationError::DuplicateOutput { output: "data.csv".into(), steps: vec!["import".into(), "import".into()] },
+            ValidationError::MissingInput { step: "merge".into(), input: "other.csv".into() },
+            ValidationError::MissingWorkflowOutput { output: "result.csv".into() },
+            ValidationError::InvalidFileName { step: None, file: "../etc/passwd".into() },
+        ]);
+    }
+
+    #[test]
+    fn reports_resources_above_site_limits() {
+        let mut greedy = step("greedy", &[], "out.csv");
+        greedy.resources = Some(StepResources { cpus: Some(8.0), memory: Some(0), pids: Some(100), ..Default::default() });
+        let workflow = Workflow { output: vec![], steps: vec![greedy], max_parallelism: None, timeout: None, parameters: Default::default() };
+        let limits = SiteLimits { resources: StepResources { cpus: Some(2.0), pids: Some(100), ..Default::default() }, ..Default::default() };
+        assert_eq!(validate_workflow(&workflow, &limits).unwrap_err(), vec![
+            ValidationError::InvalidResource { step: "greedy".into(), resource: "memory".into() },
+            ValidationError::ResourceLimitExceeded { step: "greedy".into(), resource: "cpus".into(), requested: "8".into(), limit: "2".into() },
+        ]);
+    }
+
+    #[test]
+    fn reports_timeouts_above_site_limits() {
+        let mut slow = step("slow", &[], "out.csv");
+        slow.timeout = Some(Duration::from_secs(3600));
+        let mut broken = step("broken", &[], "other.csv");
+        broken.timeout = Some(Duration::ZERO);
+        let workflow = Workflow { output: vec![], steps: vec![slow, broken], max_parallelism: None, timeout: Some(Duration::from_secs(7200)), parameters: Default::default() };
+        let limits = SiteLimits { max_step_timeout: Some(Duration::from_secs(600)), max_workflow_timeout: Some(Duration::from_secs(3600)), ..Default::default() };
+        assert_eq!(validate_workflow(&workflow, &limits).unwrap_err(), vec![
+            ValidationError::TimeoutExceeded { step: None, requested: "7200s".into(), limit: "3600s".into() },
+            ValidationError::TimeoutExceeded { step: Some("slow".into()), requested: "3600s".into(), limit: "600s".into() },
+            ValidationError::InvalidTimeout { step: Some("broken".into()) },
+        ]);
+    }
+
+    #[test]
+    fn reports_disallowed_images() {
+        let mut foreign = step("foreign", &[], "out.csv");
+        foreign.image = "evil.example.com/miner:latest".into();
+        let workflow = Workflow { output: vec![], steps: vec![step("ok", &[], "ok.csv"), foreign], max_parallelism: None, timeout: None, parameters: Default::default() };
+        let limits = SiteLimits { images: ImagePolicy::new(vec!["docker.io/library/*".into()], false, false), ..Default::default() };
+        assert_eq!(validate_workflow(&workflow, &limits), Ok(()));
+        assert_eq!(check_images(&workflow, &limits.images), vec![
+            ValidationError::ImageNotAllowed { step: "foreign".into(), image: "evil.example.com/miner:latest".into(), reason: "evil.example.com/miner is not an allowed repository".into() },
+        ]);
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let workflow = Workflow {
+            output: vec![],
+            steps: vec![step("a", &["b.csv"], "a.csv"), step("b", &["a.csv"], "b.csv"), step("c", &[], "c.csv")],
+            max_parallelism: None,
+            timeout: None,
+            parameters: Default::default(),
+        };
+        let errors = validate_workflow(&workflow, &SiteLimits::default()).unwrap_err();
+        assert!(matches!(&errors[..], [ValidationError::Cycle { detail }] if detail.contains("a, b")));
+    }
+}
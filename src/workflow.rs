@@ -1,47 +1,411 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use base64::Engine;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use uuid::Uuid;
 
-use crate::{beam::BeamTask, error::ExecutorError};
+use crate::{beam::{AppId, BeamTask, FailureStrategy}, catalog::{Catalog, CatalogRef}, error::ExecutorError, format::Format, schema::{parse_versioned, SchemaVersion}, policy::ImagePolicy, template::{instantiate, Parameter}, validation::ValidationError};
 
 
-#[derive(Debug, Copy, Clone, Hash, Deserialize)]
-pub(crate) enum Executor {
-    DockerExecutor,
-    HPCExecutor
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowSteps {
+    pub name: String,
+    pub image: String,
+    /// Environment variables in `KEY=value` form
+    pub env: Option<Vec<String>>,
+    /// Names of files produced by other steps that this step reads
+    pub input: Option<Vec<String>>,
+    /// Name of the file this step produces
+    pub output: String,
+    /// Limits for the step's container; unset limits default to the site maximum
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<StepResources>,
+    /// How long the step may run, in Beam's ttl notation like `90s` or `2h`
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timeout")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+}
 
+/// Resources available to a step's container. Also used for the site-wide maximums.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct StepResources {
+    /// Number of CPUs, fractions are allowed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<f64>,
+    /// Memory in bytes, or with a unit like `512M` or `2G`
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "byte_size")]
+    #[schemars(with = "Option<byte_size::Size>")]
+    pub memory: Option<u64>,
+    /// Maximum number of processes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids: Option<i64>,
+    /// Size of the tmpfs mounted at `/tmp`
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "byte_size")]
+    #[schemars(with = "Option<byte_size::Size>")]
+    pub tmpfs: Option<u64>,
+    /// Size of the container's writable layer; requires a storage driver that supports size quotas
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "byte_size")]
+    #[schemars(with = "Option<byte_size::Size>")]
+    pub disk: Option<u64>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub(crate) struct WorkflowSteps {
-    name: String,
-    image: String,
-    env: Option<Vec<String>>,
-    input: Option<Vec<String>>,
-    output: String
+impl StepResources {
+    /// The requested limits, falling back to `max` where the step requests none.
+    pub fn within(&self, max: &StepResources) -> StepResources {
+        StepResources {
+            cpus: self.cpus.or(max.cpus),
+            memory: self.memory.or(max.memory),
+            pids: self.pids.or(max.pids),
+            tmpfs: self.tmpfs.or(max.tmpfs),
+            disk: self.disk.or(max.disk),
+        }
+    }
+
+    /// Names, requested values and maximums of all limits exceeding `max`.
+    pub fn exceeding(&self, max: &StepResources) -> Vec<(&'static str, String, String)> {
+        fn check<T: PartialOrd + ToString>(name: &'static str, requested: Option<T>, max: Option<T>) -> Option<(&'static str, String, String)> {
+            match (requested, max) {
+                (Some(requested), Some(max)) if requested > max => Some((name, requested.to_string(), max.to_string())),
+                _ => None,
+            }
+        }
+        [
+            check("cpus", self.cpus, max.cpus),
+            check("memory", self.memory, max.memory),
+            check("pids", self.pids, max.pids),
+            check("tmpfs", self.tmpfs, max.tmpfs),
+            check("disk", self.disk, max.disk),
+        ].into_iter().flatten().collect()
+    }
+
+    /// Names of all limits that are zero or negative.
+    pub fn invalid(&self) -> Vec<&'static str> {
+        [
+            ("cpus", matches!(self.cpus, Some(cpus) if !(cpus > 0.0 && cpus.is_finite()))),
+            ("memory", self.memory == Some(0)),
+            ("pids", matches!(self.pids, Some(pids) if pids <= 0)),
+            ("tmpfs", self.tmpfs == Some(0)),
+            ("disk", self.disk == Some(0)),
+        ].into_iter().filter(|(_, invalid)| *invalid).map(|(name, _)| name).collect()
+    }
+}
+
+/// Parses sizes like `1024`, `512k`, `512M`, `2GiB` into bytes, using binary units.
+pub(crate) fn parse_byte_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("{size:?} is not a valid size"))?;
+    let factor: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err(format!("Unknown unit in size {size:?}")),
+    };
+    number.checked_mul(factor).ok_or_else(|| format!("Size {size:?} is too large"))
+}
+
+/// (De)serializes optional durations in Beam's ttl notation.
+mod timeout {
+    use std::time::Duration;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(timeout: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match timeout {
+            Some(timeout) if timeout.subsec_nanos() == 0 => serializer.serialize_str(&format!("{}s", timeout.as_secs())),
+            Some(timeout) => serializer.serialize_str(&format!("{}ms", timeout.as_millis())),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|timeout| crate::beam::parse_ttl(&timeout).map_err(D::Error::custom))
+            .transpose()
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// (De)serializes optional sizes given either as a number of bytes or as a string with a unit.
+mod byte_size {
+    use schemars::JsonSchema;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    /// A size in bytes, or a string with a unit like `512M`
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(untagged)]
+    pub enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(size: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match size {
+            Some(size) => serializer.serialize_u64(*size),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        match Option::<Size>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(Size::Bytes(size)) => Ok(Some(size)),
+            Some(Size::Text(size)) => super::parse_byte_size(&size).map(Some).map_err(D::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub(crate) struct Workflow {
-    output: Vec<String>,
-    steps: Vec<WorkflowSteps>
+    /// Files produced by the steps that are sent back to the requester
+    pub output: Vec<String>,
+    pub steps: Vec<WorkflowSteps>,
+    /// How many steps of this workflow may run at the same time, on top of the site-wide limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_parallelism: Option<usize>,
+    /// How long all steps together may run, in Beam's ttl notation like `90s` or `2h`
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timeout")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Values the task supplies, referenced as `${name}` in the steps' `image`, `env`, `input` and `output`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub parameters: BTreeMap<String, Parameter>,
+}
+
+/// Limits the site imposes on every workflow.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SiteLimits {
+    /// Maximum resources of a step, also applied to steps that request none
+    pub resources: StepResources,
+    /// Timeout of steps that do not set one
+    pub default_step_timeout: Option<Duration>,
+    pub max_step_timeout: Option<Duration>,
+    /// Maximum timeout of a workflow, also applied to workflows that do not set one
+    pub max_workflow_timeout: Option<Duration>,
+    pub images: ImagePolicy,
+}
+
+impl SiteLimits {
+    pub fn step_timeout(&self, step: &WorkflowSteps) -> Option<Duration> {
+        step.timeout.or(self.default_step_timeout).or(self.max_step_timeout)
+    }
+
+    pub fn workflow_timeout(&self, workflow: &Workflow) -> Option<Duration> {
+        workflow.timeout.or(self.max_workflow_timeout)
+    }
+}
+
+/// What happens to steps still running in other branches once a step has failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum StepFailurePolicy {
+    /// Stop running steps right away
+    Cancel,
+    /// Let running steps finish, but do not start new ones
+    Drain,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl WorkflowSteps {
+    pub fn inputs(&self) -> &[String] {
+        self.input.as_deref().unwrap_or_default()
+    }
+}
+
+/// How a single step of a workflow run ended.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StepStatus {
+    Succeeded,
+    Failed,
+    /// Killed by the kernel for exceeding its memory limit
+    #[serde(rename = "out_of_memory")]
+    OutOfMemory,
+    /// Stopped because the step's or the workflow's timeout was exceeded
+    #[serde(rename = "timed_out")]
+    TimedOut,
+    /// Stopped because a step in another branch failed
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct StepReport {
+    pub name: String,
+    pub status: StepStatus,
+    pub exit_code: Option<i64>,
+    /// Combined stdout and stderr of the step
+    pub logs: String,
+}
+
+/// A file from `Workflow.output`, base64 encoded unless it is valid UTF-8.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct OutputFile {
+    pub encoding: &'static str,
+    pub content: String,
+}
+
+impl From<Vec<u8>> for OutputFile {
+    fn from(content: Vec<u8>) -> Self {
+        match String::from_utf8(content) {
+            Ok(content) => OutputFile { encoding: "utf8", content },
+            Err(e) => OutputFile { encoding: "base64", content: base64::engine::general_purpose::STANDARD.encode(e.into_bytes()) },
+        }
+    }
+}
+
+/// Body of the result of a natively executed workflow.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RunReport {
+    pub steps: Vec<StepReport>,
+    pub outputs: BTreeMap<String, OutputFile>,
+}
+
+/// The executor running a workflow, given by its name since schema version 2.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(transparent)]
 pub(crate) struct ExecutorInfo{
-    pub name: Executor,
+    /// One of the executors configured with `--executors`
+    pub name: String,
+}
+
+/// The Beam task an `ExecutionTask` was created from.
+#[derive(Debug, Clone)]
+pub(crate) struct TaskContext {
+    pub id: Uuid,
+    pub from: AppId,
+    pub ttl: Duration,
+    pub expires_at: Instant,
+    pub failure_strategy: FailureStrategy,
+    pub metadata: String,
+}
+
+impl From<&BeamTask> for TaskContext {
+    fn from(task: &BeamTask) -> Self {
+        TaskContext {
+            id: task.id,
+            from: task.from.clone(),
+            ttl: task.ttl,
+            expires_at: task.expires_at(),
+            failure_strategy: task.failure_strategy.clone(),
+            metadata: task.metadata.clone(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// The part of an `ExecutionTask` sent by the requester in `BeamTask.body`: either an inline `executor` and
+/// `workflow`, or a reference to a workflow in the site's catalog.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[schemars(title = "Task body")]
+pub(crate) struct TaskBody {
+    /// Version of this schema the body is written against; bodies without it are read as version 1
+    #[serde(rename = "schema_version")]
+    _schema_version: SchemaVersion,
+    executor: Option<ExecutorInfo>,
+    workflow: Option<Workflow>,
+    catalog: Option<CatalogRef>,
+    /// Values for `Workflow.parameters`
+    #[serde(default)]
+    parameters: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct ExecutionTask {
+    pub context: TaskContext,
     pub executor: ExecutorInfo,
     pub workflow: Workflow
 }
 
-impl TryFrom<BeamTask> for ExecutionTask {
-    type Error = ExecutorError;
+impl ExecutionTask {
+    /// Parses the task's body as JSON or YAML, as hinted by the task's metadata or else detected from the body,
+    /// upgrades it to the current schema version, looks up catalog references in `catalog` and instantiates the workflow with the supplied parameters.
+    pub fn parse(value: BeamTask, catalog: &Catalog) -> Result<Self, ExecutorError> {
+        let format = Format::from_metadata(&value.metadata).unwrap_or_else(|| Format::detect(&value.body));
+        let body: TaskBody = parse_versioned(&value.body, format).map_err(ExecutorError::ParsingError)?;
+        let (executor, workflow) = match body {
+            TaskBody { executor: Some(executor), workflow: Some(workflow), catalog: None, .. } if catalog.inline_allowed() => (executor, workflow),
+            TaskBody { executor: Some(_), workflow: Some(_), catalog: None, .. } => {
+                return Err(ExecutorError::TaskRejected("This site only runs workflows from its catalog".into()));
+            },
+            TaskBody { executor: None, workflow: None, catalog: Some(ref reference), .. } => {
+                let entry = catalog.lookup(reference).ok_or_else(|| ExecutorError::ValidationFailed(vec![
+                    ValidationError::UnknownCatalogWorkflow { name: reference.name.clone(), version: reference.version.clone() }
+                ]))?;
+                (entry.executor, entry.workflow)
+            },
+            _ => return Err(ExecutorError::ParsingError("Task body must contain either executor and workflow, or a catalog reference".into())),
+        };
+        Ok(ExecutionTask {
+            context: TaskContext::from(&value),
+            executor,
+            workflow: instantiate(workflow, &body.parameters).map_err(ExecutorError::ValidationFailed)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_task;
+
+    #[test]
+    fn task_bodies_may_be_yaml() {
+        let body = "schema_version: 2\nexecutor: DockerExecutor\nworkflow:\n  output: [out.csv]\n  steps:\n    - {name: s, image: i, env: null, input: null, output: out.csv}\n";
+        let catalog = Catalog::load(None, true).unwrap();
+        let task = ExecutionTask::parse(test_task(body, Duration::from_secs(10)), &catalog).unwrap();
+        assert_eq!(task.workflow.steps[0].output, "out.csv");
+        let flow = "{schema_version: 2, executor: DockerExecutor, workflow: {output: [out.csv], steps: [{name: s, image: i, env: null, input: null, output: out.csv}]}}";
+        let task = ExecutionTask::parse(test_task(flow, Duration::from_secs(10)), &catalog).unwrap();
+        assert_eq!(task.workflow.steps[0].output, "out.csv");
+
+        let mut hinted = test_task("{schema_version: 2, executor: [DockerExecutor]}", Duration::from_secs(10));
+        hinted.metadata = r#"{"format":"yaml"}"#.into();
+        let error = ExecutionTask::parse(hinted, &catalog).unwrap_err().to_string();
+        assert!(error.contains("invalid YAML"), "{error}");
+        let error = ExecutionTask::parse(test_task("{\"schema_version\": 2,\n\"executor\": 1}", Duration::from_secs(10)), &catalog).unwrap_err().to_string();
+        assert!(error.contains("invalid JSON at line 2, column"), "{error}");
+    }
+
+    #[test]
+    fn parses_byte_sizes() {
+        assert_eq!(parse_byte_size("1024"), Ok(1024));
+        assert_eq!(parse_byte_size("512k"), Ok(512 * 1024));
+        assert_eq!(parse_byte_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_byte_size("2 GiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_byte_size("").is_err());
+        assert!(parse_byte_size("5 parsecs").is_err());
+        assert!(parse_byte_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn resources_accept_numbers_and_units() {
+        let resources: StepResources = serde_json::from_str(r#"{"cpus":0.5,"memory":"256M","pids":64,"tmpfs":1048576}"#).unwrap();
+        assert_eq!(resources, StepResources { cpus: Some(0.5), memory: Some(256 << 20), pids: Some(64), tmpfs: Some(1 << 20), disk: None });
+        assert_eq!(serde_json::to_string(&resources).unwrap(), r#"{"cpus":0.5,"memory":268435456,"pids":64,"tmpfs":1048576}"#);
+    }
+
+    #[test]
+    fn timeouts_use_ttl_notation() {
+        let workflow: Workflow = serde_json::from_str(r#"{"output":[],"steps":[{"name":"s","image":"i","env":null,"input":null,"output":"o","timeout":"90s"}],"timeout":"2h"}"#).unwrap();
+        assert_eq!(workflow.timeout, Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(workflow.steps[0].timeout, Some(Duration::from_secs(90)));
+        assert!(serde_json::to_string(&workflow).unwrap().contains(r#""timeout":"7200s""#));
+
+        let limits = SiteLimits { default_step_timeout: Some(Duration::from_secs(60)), max_step_timeout: Some(Duration::from_secs(600)), ..Default::default() };
+        assert_eq!(limits.step_timeout(&workflow.steps[0]), Some(Duration::from_secs(90)));
+        let mut step = workflow.steps[0].clone();
+        step.timeout = None;
+        assert_eq!(limits.step_timeout(&step), Some(Duration::from_secs(60)));
+        assert_eq!(limits.workflow_timeout(&Workflow { timeout: None, ..workflow }), None);
+    }
 
-    fn try_from(value: BeamTask) -> Result<Self, Self::Error> {
-        let result: Result<Self, Self::Error> = serde_json::from_str(&value.body).map_err(|e| ExecutorError::ParsingError(e.to_string()));
-        result
-        // Todo: Get TaskId and put it in the ExecutionTask
+    #[test]
+    fn resources_are_limited_by_site_maximum() {
+        let max = StepResources { cpus: Some(2.0), memory: Some(1 << 30), ..Default::default() };
+        let requested = StepResources { cpus: Some(4.0), pids: Some(10), ..Default::default() };
+        assert_eq!(requested.exceeding(&max), vec![("cpus", "4".to_string(), "2".to_string())]);
+        assert_eq!(requested.within(&max), StepResources { cpus: Some(4.0), memory: Some(1 << 30), pids: Some(10), ..Default::default() });
+        assert_eq!(StepResources { cpus: Some(0.0), pids: Some(-1), ..Default::default() }.invalid(), vec!["cpus", "pids"]);
     }
 }